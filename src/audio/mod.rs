@@ -71,7 +71,7 @@ impl PowerControl {
 
 const SAMPLE_PACKET_SIZE: usize = 32;
 const CYCLES_PER_SECOND: usize = 154 * 456 * 60;
-const INPUT_SAMPLE_RATE: f64 = 131_072.0;
+pub const INPUT_SAMPLE_RATE: f64 = 131_072.0;
 
 pub type SamplePacket = Box<[Stereo<f32>]>;
 
@@ -91,6 +91,7 @@ pub struct AudioDevice {
     // Managing output of samples
     sample_buffer:      Vec<Stereo<f32>>,
     sender:             Option<Sender<SamplePacket>>,
+    recorder:           Option<Sender<SamplePacket>>,
     cycle_count:        f64,
     cycles_per_sample:  f64,
 
@@ -116,8 +117,9 @@ impl AudioDevice {
 
             sample_buffer:      Vec::new(),
             sender:             None,
+            recorder:           None,
             cycle_count:        0.0,
-            cycles_per_sample:  0.0,
+            cycles_per_sample:  (CYCLES_PER_SECOND as f64) / INPUT_SAMPLE_RATE,
 
             vol_left:           0.0,
             vol_right:          0.0,
@@ -130,9 +132,11 @@ impl AudioDevice {
     // Call to enable audio on the appropriate thread (this should be done before any processing)
     pub fn enable_audio(&mut self, sender: Sender<SamplePacket>) {
         self.sender = Some(sender);
+    }
 
-        let seconds_per_sample = 1.0 / INPUT_SAMPLE_RATE;
-        self.cycles_per_sample = seconds_per_sample * (CYCLES_PER_SECOND as f64);
+    // Send a copy of all samples for recording, or stop recording with None.
+    pub fn set_recorder(&mut self, recorder: Option<Sender<SamplePacket>>) {
+        self.recorder = recorder;
     }
 
     pub fn clock(&mut self, cycles: u32) {
@@ -151,6 +155,10 @@ impl AudioDevice {
             // Output to audio thread
             if self.sample_buffer.len() > SAMPLE_PACKET_SIZE {
                 let sample_packet = self.sample_buffer.drain(..).collect::<SamplePacket>();
+                if let Some(r) = &self.recorder {
+                    // The recorder may have been dropped without stopping.
+                    let _ = r.send(sample_packet.clone());
+                }
                 if let Some(s) = &self.sender {
                     s.send(sample_packet).expect("Error sending!");
                 }
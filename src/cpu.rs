@@ -4,7 +4,12 @@ use crossbeam_channel::Sender;
 
 use crate::{
     audio::SamplePacket,
-    mem::{MemBus, MemDevice},
+    mem::{MemBus, MemDevice, CameraSource},
+    video::{
+        ColourCorrection,
+        UserPalette,
+        Layers
+    },
     interrupt::*,
     joypad::{
         Buttons,
@@ -12,10 +17,6 @@ use crate::{
     }
 };
 
-use std::sync::{
-    Arc, Mutex
-};
-
 bitflags! {
     #[derive(Default)]
     struct CPUFlags: u8 {
@@ -162,8 +163,8 @@ impl CPU {
         return true;
     }
 
-    pub fn frame_update(&mut self, frame: Arc<Mutex<[u8]>>) {
-        self.mem.frame(frame);
+    pub fn frame_update(&mut self) {
+        self.mem.frame();
         self.mem.flush_cart();
     }
 
@@ -171,17 +172,61 @@ impl CPU {
         self.mem.enable_audio(sender);
     }
 
-    pub fn set_button(&mut self, button: Buttons, val: bool) {
-        self.mem.set_button(button, val);
+    pub fn set_audio_recorder(&mut self, recorder: Option<Sender<SamplePacket>>) {
+        self.mem.set_audio_recorder(recorder);
     }
 
-    pub fn set_direction(&mut self, direction: Directions, val: bool) {
-        self.mem.set_direction(direction, val);
+    pub fn set_button(&mut self, player: usize, button: Buttons, val: bool) {
+        self.mem.set_button(player, button, val);
+    }
+
+    pub fn set_direction(&mut self, player: usize, direction: Directions, val: bool) {
+        self.mem.set_direction(player, direction, val);
     }
 
     pub fn cart_name(&self) -> String {
         self.mem.cart_name()
     }
+
+    pub fn rom_error(&mut self) -> Option<String> {
+        self.mem.rom_error()
+    }
+
+    pub fn set_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        self.mem.set_camera_source(source);
+    }
+
+    pub fn enable_pixel_fifo(&mut self, enable: bool) {
+        self.mem.enable_pixel_fifo(enable);
+    }
+
+    pub fn set_palette(&mut self, palette: UserPalette) -> Result<(), String> {
+        self.mem.set_palette(palette)
+    }
+
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.mem.set_colour_correction(correction);
+    }
+
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.mem.set_layers(layers);
+    }
+
+    pub fn set_object_visible(&mut self, index: usize, visible: bool) {
+        self.mem.set_object_visible(index, visible);
+    }
+
+    pub fn enable_sgb_border(&mut self, enable: bool) {
+        self.mem.enable_sgb_border(enable);
+    }
+
+    pub fn frame_size(&self) -> (usize, usize) {
+        self.mem.frame_size()
+    }
+
+    pub fn output_frame(&self, output: &mut [u8]) {
+        self.mem.output_frame(output);
+    }
 }
 
 // Top level internals
@@ -962,4 +1007,24 @@ impl CPU {
     pub fn get_mem_at(&self, loc: u16) -> u8 {
         self.mem.read(loc)
     }
+
+    #[cfg(feature = "debug")]
+    pub fn draw_tile_sheet(&self, palette: usize, output: &mut [u8]) {
+        self.mem.draw_tile_sheet(palette, output);
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn draw_tile_map(&self, map_1: bool, output: &mut [u8]) {
+        self.mem.draw_tile_map(map_1, output);
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn get_object_info(&self) -> Vec<crate::debug::ObjectInfo> {
+        self.mem.get_object_info()
+    }
+
+    #[cfg(feature = "debug")]
+    pub fn draw_palettes(&self, output: &mut [u8]) {
+        self.mem.draw_palettes(output);
+    }
 }
\ No newline at end of file
@@ -24,3 +24,28 @@ impl CPUState {
                 self.pc, self.sp)
     }
 }
+
+// Sizes of the VRAM viewer images. All viewers output RGBA8888.
+pub const TILE_SHEET_WIDTH: usize = 256;    // 16 tiles for each bank, side by side.
+pub const TILE_SHEET_HEIGHT: usize = 192;   // 24 rows of tiles.
+pub const TILE_MAP_SIZE: usize = 256;       // Width and height of a full tile map.
+pub const PALETTE_VIEW_WIDTH: usize = 32;   // 4 colours, 8 pixels each.
+pub const PALETTE_VIEW_HEIGHT: usize = 128; // 16 palettes, 8 pixels each.
+pub const OBJECT_PREVIEW_WIDTH: usize = 8;  // Objects are 8 or 16 pixels high.
+
+// An entry in OAM, with a preview of how it looks.
+pub struct ObjectInfo {
+    pub index:      usize,
+    pub x:          u8,
+    pub y:          u8,
+    pub tile_num:   u8,
+    pub flags:      u8,
+
+    pub above_bg:   bool,
+    pub flip_x:     bool,
+    pub flip_y:     bool,
+    pub palette:    u8,     // 0 or 1 for GB, 0-7 for CGB
+    pub bank:       u8,
+
+    pub preview:    Vec<u8> // RGBA8888. Transparent pixels have alpha 0.
+}
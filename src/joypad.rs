@@ -29,29 +29,58 @@ enum Select {
 const SELECT_DIRECTION: u8  = 4;
 const SELECT_BUTTONS: u8    = 5;
 
+pub const MAX_PLAYERS: usize = 4;
+const MLT_REQ: u8 = 0x11;
+
 pub struct Joypad {
-    buttons:    Buttons,
-    directions: Directions,
+    buttons:    [Buttons; MAX_PLAYERS],
+    directions: [Directions; MAX_PLAYERS],
 
     selector:   Select,
-    change:     bool
+    change:     bool,
+
+    // SGB multiplayer
+    num_players:    usize,
+    player:         usize,  // Currently selected joypad.
+    p15:            bool,
+
+    sgb_packets:    Option<PacketReader>,
 }
 
 impl Joypad {
     pub fn new() -> Self {
         Joypad {
-            buttons:    Buttons::default(),
-            directions: Directions::default(),
+            buttons:    [Buttons::default(); MAX_PLAYERS],
+            directions: [Directions::default(); MAX_PLAYERS],
 
             selector:   Select::None,
-            change:     false
+            change:     false,
+
+            num_players:    1,
+            player:         0,
+            p15:            true,
+
+            sgb_packets:    None,
         }
     }
 
+    // Listen for Super Game Boy command packets.
+    pub fn enable_sgb(&mut self) {
+        self.sgb_packets = Some(PacketReader::new());
+    }
+
+    // Get the last complete SGB command, if there is a new one.
+    pub fn sgb_command(&mut self) -> Option<Vec<u8>> {
+        self.sgb_packets.as_mut().and_then(|p| p.command.take())
+    }
+
+    // In SGB multiplayer mode, the ID of the selected joypad is read when nothing is selected.
+    // IDs count down from 0xF for player 1.
     pub fn read(&self) -> u8 {
         match self.selector {
-            Select::Direction => (!self.directions.bits() & 0xF),
-            Select::Button => (!self.buttons.bits() & 0xF),
+            Select::Direction => !self.directions[self.player].bits() & 0xF,
+            Select::Button => !self.buttons[self.player].bits() & 0xF,
+            Select::None if self.num_players > 1 => 0xF - (self.player as u8),
             Select::None => 0
         }
     }
@@ -64,15 +93,36 @@ impl Joypad {
         } else {
             Select::None
         };
+
+        // The next joypad is selected when P15 goes high.
+        let p15 = test_bit!(val, SELECT_BUTTONS);
+        if p15 && !self.p15 {
+            self.player = (self.player + 1) % self.num_players;
+        }
+        self.p15 = p15;
+
+        if let Some(packets) = &mut self.sgb_packets {
+            packets.write(val);
+            if let Some(command) = &packets.command {
+                if (command[0] >> 3) == MLT_REQ {
+                    self.num_players = match command[1] & 3 {
+                        1 => 2,
+                        3 => 4,
+                        _ => 1,
+                    };
+                    self.player = 0;
+                }
+            }
+        }
     }
 
-    pub fn set_direction(&mut self, direction: Directions, val: bool) {
-        self.directions.set(direction, val);
+    pub fn set_direction(&mut self, player: usize, direction: Directions, val: bool) {
+        self.directions[player].set(direction, val);
         self.change = self.change || val;
     }
 
-    pub fn set_button(&mut self, button: Buttons, val: bool) {
-        self.buttons.set(button, val);
+    pub fn set_button(&mut self, player: usize, button: Buttons, val: bool) {
+        self.buttons[player].set(button, val);
         self.change = self.change || val;
     }
 
@@ -82,3 +132,135 @@ impl Joypad {
         trigger_interrupt
     }
 }
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+
+// Reads SGB command packets.
+// A packet starts with P14 and P15 both low. Each bit is sent by pulling P14 low (0) or P15 low (1),
+// then both high. 16 bytes are sent LSB first, followed by a 0 stop bit.
+// The low 3 bits of the first byte give the number of packets in the command.
+// Packets are collected until the command is complete.
+// If a packet is interrupted by a reset, the game has given up on the command, so any packets received for it are discarded.
+struct PacketReader {
+    data:       Vec<u8>,
+    bit:        usize,
+    receiving:  bool,
+    ready:      bool,   // P14 and P15 have gone high since the last bit.
+
+    command:    Option<Vec<u8>>,
+}
+
+impl PacketReader {
+    fn new() -> Self {
+        PacketReader {
+            data:       Vec::new(),
+            bit:        0,
+            receiving:  false,
+            ready:      false,
+
+            command:    None,
+        }
+    }
+
+    fn write(&mut self, val: u8) {
+        match val & 0x30 {
+            0x00 => {
+                // Reset: throw away any incomplete command and start a new packet.
+                if self.receiving {
+                    self.data.clear();
+                }
+                self.data.extend_from_slice(&[0; PACKET_SIZE]);
+                self.bit = 0;
+                self.receiving = true;
+                self.ready = false;
+            },
+            0x30 => self.ready = true,
+            pins => if self.receiving && self.ready {
+                self.ready = false;
+                if self.bit < PACKET_BITS {
+                    if pins == 0x10 {
+                        let byte = self.data.len() - PACKET_SIZE + (self.bit / 8);
+                        self.data[byte] |= bit!(self.bit % 8);
+                    }
+                    self.bit += 1;
+                } else {
+                    // Stop bit.
+                    self.receiving = false;
+                    let num_packets = (self.data[0] & 7) as usize;
+                    if num_packets == 0 {
+                        // Not a valid header.
+                        self.data.clear();
+                    } else if self.data.len() >= num_packets * PACKET_SIZE {
+                        self.command = Some(std::mem::take(&mut self.data));
+                    }
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Send a packet bit by bit, with the reset pulse and stop bit.
+    fn send_packet(reader: &mut PacketReader, packet: &[u8; PACKET_SIZE]) {
+        reader.write(0x00);
+        reader.write(0x30);
+        for i in 0..PACKET_BITS {
+            reader.write(if test_bit!(packet[i / 8], i % 8) {0x10} else {0x20});
+            reader.write(0x30);
+        }
+        reader.write(0x20);
+        reader.write(0x30);
+    }
+
+    fn packet(data: &[u8]) -> [u8; PACKET_SIZE] {
+        let mut packet = [0; PACKET_SIZE];
+        packet[..data.len()].copy_from_slice(data);
+        packet
+    }
+
+    #[test]
+    fn single_packet() {
+        let mut reader = PacketReader::new();
+        let pal01 = packet(&[0x01, 0xFF, 0x7F, 0x00, 0x00]);
+        send_packet(&mut reader, &pal01);
+        assert_eq!(reader.command.take(), Some(pal01.to_vec()));
+    }
+
+    #[test]
+    fn multi_packet() {
+        // ATTR_LIN with 2 packets. The second packet starts with the same byte as the header.
+        let mut reader = PacketReader::new();
+        let first = packet(&[0x2A, 20, 0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8A, 0x8B, 0x8C, 0x8D]);
+        let second = packet(&[0x2A, 0x8F, 0x90, 0x91, 0x92, 0x93]);
+        send_packet(&mut reader, &first);
+        assert_eq!(reader.command, None);
+        send_packet(&mut reader, &second);
+        assert_eq!(reader.command.take(), Some([first, second].concat()));
+    }
+
+    #[test]
+    fn interrupted_packet() {
+        // A reset in the middle of a packet discards the command.
+        let mut reader = PacketReader::new();
+        send_packet(&mut reader, &packet(&[0x2A, 1]));
+        reader.write(0x00);
+        reader.write(0x30);
+        reader.write(0x10);
+        reader.write(0x30);
+        let pal01 = packet(&[0x01, 0x1F, 0x00]);
+        send_packet(&mut reader, &pal01);
+        assert_eq!(reader.command.take(), Some(pal01.to_vec()));
+    }
+
+    #[test]
+    fn invalid_header() {
+        let mut reader = PacketReader::new();
+        send_packet(&mut reader, &packet(&[0x00]));
+        assert_eq!(reader.command, None);
+        assert!(reader.data.is_empty());
+    }
+}
@@ -8,32 +8,50 @@ mod timer;
 mod audio;
 mod interrupt;
 mod joypad;
+mod recorder;
 
 #[cfg(feature = "debug")]
 pub mod debug;
 
 pub use video::{
-    UserPalette
+    Colour,
+    PaletteColours,
+    sgbpalettes::SGBPalette,
+    UserPalette,
+    CompatibilityPreset,
+    PixelFormat,
+    ColourCorrection,
+    FrameBlend,
+    ScaleFilter,
+    Layers,
+    Screenshot
 };
 
 use joypad::{
     Buttons,
     Directions
 };
-
-use std::sync::{
-    Arc,
-    Mutex
-};
+pub use joypad::MAX_PLAYERS;
 
 use crossbeam_channel::unbounded;
 
 use audio::Resampler;
+use video::FrameBlender;
+use recorder::Recorder;
 use cpu::CPU;
 use mem::MemBus;
-pub use mem::ROMType;
+pub use mem::{
+    ROMType,
+    ROM,
+    CameraSource,
+    StillImage,
+    CAMERA_WIDTH,
+    CAMERA_HEIGHT
+};
 
+// Frame sizes for 4-byte pixel formats.
 pub const FRAME_SIZE_BYTES: usize = 160 * 144 * 4;
+pub const SGB_FRAME_SIZE_BYTES: usize = 256 * 224 * 4;    // With the Super Game Boy border.
 
 pub enum Button {
     Up,
@@ -49,18 +67,29 @@ pub enum Button {
 pub struct RustBoy {
     cpu:            CPU,
 
-    frame:          Arc<Mutex<[u8; FRAME_SIZE_BYTES]>>,
+    pixel_format:   PixelFormat,
+    blender:        FrameBlender,
+    filter:         ScaleFilter,
+    unscaled:       Vec<u8>,    // Output frame before the filter is applied.
+    recorder:       Option<Recorder>,
+    recorded:       Vec<u8>,    // Output frame for the recorder.
 }
 
 impl RustBoy {
-    pub fn new(rom: ROMType, save_file_name: &str, palette: UserPalette) -> Box<Self> {
-        let mem = MemBus::new(rom, save_file_name, palette);
+    // The frame passed to frame() should be frame_size() pixels of the pixel format.
+    pub fn new(rom: ROMType, save_file_name: &str, palette: UserPalette, pixel_format: PixelFormat) -> Box<Self> {
+        let mem = MemBus::new(rom, save_file_name, palette, pixel_format);
         let cpu = CPU::new(mem);
 
         Box::new(RustBoy {
             cpu:            cpu,
 
-            frame:          Arc::new(Mutex::new([255; FRAME_SIZE_BYTES])),
+            pixel_format,
+            blender:        FrameBlender::new(pixel_format),
+            filter:         ScaleFilter::None,
+            unscaled:       Vec::new(),
+            recorder:       None,
+            recorded:       Vec::new(),
         })
     }
 
@@ -75,33 +104,164 @@ impl RustBoy {
     }
 
     // Call every 1/60 seconds.
+    // With the threads feature, the frame is drawn while the next one runs, so the output is one frame behind.
+    // Panics if the frame isn't exactly frame_size() pixels of the pixel format.
     pub fn frame(&mut self, frame: &mut [u8]) {
-        self.cpu.frame_update(self.frame.clone());    // Draw video and read inputs
+        let (width, height) = self.frame_size();
+        assert_eq!(frame.len(), width * height * self.pixel_format.bytes_per_pixel(), "frame size doesn't match frame_size()");
+
+        self.cpu.frame_update();    // Read inputs
 
         while self.cpu.step() {}    // Execute up to v-blanking
 
-        let new_frame = self.frame.lock().unwrap();
-        frame.copy_from_slice(&(*new_frame));
+        if self.filter == ScaleFilter::None {
+            self.cpu.output_frame(frame);
+            self.blender.apply(frame);
+        } else {
+            let (width, height) = self.cpu.frame_size();
+            self.unscaled.resize(width * height * self.pixel_format.bytes_per_pixel(), 0);
+            self.cpu.output_frame(&mut self.unscaled);
+            self.blender.apply(&mut self.unscaled);
+            self.filter.apply(&self.unscaled, width, height, frame, self.pixel_format);
+        }
+
+        if let Some(recorder) = &mut self.recorder {
+            let (width, height) = self.cpu.frame_size();
+            self.recorded.resize(width * height * self.pixel_format.bytes_per_pixel(), 0);
+            self.cpu.output_frame(&mut self.recorded);
+            recorder.write_frame(&self.recorded, width, height);
+        }
+    }
+
+    // Record every frame (before blending and filtering) to a Y4M file, and the audio to a WAV file.
+    // The recording is timed by the emulator, not the real time taken to run it.
+    // The frame size must not change while recording.
+    pub fn start_recording(&mut self, video_file_name: &str, audio_file_name: &str) -> Result<(), String> {
+        self.stop_recording()?;
+
+        let (width, height) = self.cpu.frame_size();
+        let (sample_send, sample_recv) = unbounded();
+        self.recorder = Some(Recorder::new(video_file_name, audio_file_name, width, height, self.pixel_format, sample_recv)?);
+        self.cpu.set_audio_recorder(Some(sample_send));
+        Ok(())
+    }
+
+    // Finish the recording. Returns any error that happened while recording.
+    pub fn stop_recording(&mut self) -> Result<(), String> {
+        self.cpu.set_audio_recorder(None);
+        if let Some(mut recorder) = self.recorder.take() {
+            recorder.finish()
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn set_button(&mut self, button: Button, val: bool) {
+    // Width and height of the frame output by frame().
+    // This is 160x144, or 256x224 in Super Game Boy mode with the border enabled,
+    // multiplied by the scale of the filter.
+    // This can change when the SGB border is enabled or the filter is changed, so check it before each frame.
+    pub fn frame_size(&self) -> (usize, usize) {
+        let (width, height) = self.cpu.frame_size();
+        let scale = self.filter.scale();
+        (width * scale, height * scale)
+    }
+
+    // Get a copy of the last frame, before blending and filtering.
+    pub fn screenshot(&self) -> Screenshot {
+        let (width, height) = self.cpu.frame_size();
+        let mut data = vec![0; width * height * self.pixel_format.bytes_per_pixel()];
+        self.cpu.output_frame(&mut data);
+        Screenshot {
+            width,
+            height,
+            format: self.pixel_format,
+            data,
+            filter: self.filter,
+        }
+    }
+
+    // Player is 0 for the main joypad.
+    // Players 1-3 are only read by Super Game Boy games that request multiple joypads.
+    pub fn set_button(&mut self, player: usize, button: Button, val: bool) {
         use Button::*;
 
+        if player >= MAX_PLAYERS {
+            return;
+        }
+
         match button {
-            Up      => self.cpu.set_direction(Directions::UP, val),
-            Down    => self.cpu.set_direction(Directions::DOWN, val),
-            Left    => self.cpu.set_direction(Directions::LEFT, val),
-            Right   => self.cpu.set_direction(Directions::RIGHT, val),
-            A       => self.cpu.set_button(Buttons::A, val),
-            B       => self.cpu.set_button(Buttons::B, val),
-            Start   => self.cpu.set_button(Buttons::START, val),
-            Select  => self.cpu.set_button(Buttons::SELECT, val),
+            Up      => self.cpu.set_direction(player, Directions::UP, val),
+            Down    => self.cpu.set_direction(player, Directions::DOWN, val),
+            Left    => self.cpu.set_direction(player, Directions::LEFT, val),
+            Right   => self.cpu.set_direction(player, Directions::RIGHT, val),
+            A       => self.cpu.set_button(player, Buttons::A, val),
+            B       => self.cpu.set_button(player, Buttons::B, val),
+            Start   => self.cpu.set_button(player, Buttons::START, val),
+            Select  => self.cpu.set_button(player, Buttons::SELECT, val),
         }
     }
 
     pub fn cart_name(&self) -> String {
         self.cpu.cart_name()
     }
+
+    // Check for errors loading ROM banks from a file.
+    // The emulator keeps running, with the affected banks reading as 0xFF.
+    pub fn rom_error(&mut self) -> Option<String> {
+        self.cpu.rom_error()
+    }
+
+    // Set the source of images for the Game Boy Camera.
+    // Has no effect for other cartridges.
+    pub fn set_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        self.cpu.set_camera_source(source);
+    }
+
+    // Render each line with a dot-by-dot pixel FIFO instead of all at once.
+    // This is slower, but mid-line changes to scroll, palettes and LCDC show up correctly.
+    pub fn enable_pixel_fifo(&mut self, enable: bool) {
+        self.cpu.enable_pixel_fifo(enable);
+    }
+
+    // Blend each frame with the previous one, like the slow LCD of the real thing.
+    // This only changes the output, and has no effect for indexed pixel formats.
+    pub fn set_frame_blend(&mut self, blend: FrameBlend) {
+        self.blender.set_blend(blend);
+    }
+
+    // Switch the palette while running.
+    // This fails for palettes that would change between GB, CGB and SGB modes.
+    pub fn set_palette(&mut self, palette: UserPalette) -> Result<(), String> {
+        self.cpu.set_palette(palette)
+    }
+
+    // Scale up the output frame.
+    pub fn set_scale_filter(&mut self, filter: ScaleFilter) {
+        self.filter = filter;
+    }
+
+    // Adjust Game Boy Color colours to look like a real screen.
+    // Has no effect for non-colour games.
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.cpu.set_colour_correction(correction);
+    }
+
+    // In Super Game Boy mode, output the border around the screen (on by default).
+    // If disabled, just the 160x144 screen is output.
+    pub fn enable_sgb_border(&mut self, enable: bool) {
+        self.cpu.enable_sgb_border(enable);
+    }
+
+    // Choose which layers are drawn (all by default), for debugging or ripping graphics.
+    // Hidden layers show as colour 0. The game itself is not affected.
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.cpu.set_layers(layers);
+    }
+
+    // Show or hide a single object, by its index in OAM (0-39).
+    pub fn set_object_visible(&mut self, index: usize, visible: bool) {
+        self.cpu.set_object_visible(index, visible);
+    }
 }
 
 pub struct RustBoyAudioHandle {
@@ -135,4 +295,28 @@ impl RustBoy {
     pub fn get_mem_at(&self, loc: u16) -> u8 {
         self.cpu.get_mem_at(loc)
     }
-}
\ No newline at end of file
+
+    // VRAM viewers. These all draw RGBA8888 images, with sizes given in the debug module.
+
+    // Draw all tiles: TILE_SHEET_WIDTH x TILE_SHEET_HEIGHT. CGB bank 1 is on the right.
+    // For GB, palette is 0 for BG and 1-2 for OBJ. For CGB, it is 0-7 for BG and 8-15 for OBJ.
+    pub fn draw_tile_sheet(&self, palette: usize, output: &mut [u8]) {
+        self.cpu.draw_tile_sheet(palette, output);
+    }
+
+    // Draw the tile map at 0x9800 (map 0) or 0x9C00 (map 1): TILE_MAP_SIZE x TILE_MAP_SIZE.
+    // If the background uses this map, the visible part is outlined.
+    pub fn draw_tile_map(&self, map_1: bool, output: &mut [u8]) {
+        self.cpu.draw_tile_map(map_1, output);
+    }
+
+    // Get all 40 objects in OAM, with previews.
+    pub fn get_object_info(&self) -> Vec<debug::ObjectInfo> {
+        self.cpu.get_object_info()
+    }
+
+    // Draw all palettes: PALETTE_VIEW_WIDTH x PALETTE_VIEW_HEIGHT.
+    pub fn draw_palettes(&self, output: &mut [u8]) {
+        self.cpu.draw_palettes(output);
+    }
+}
@@ -5,7 +5,10 @@ use crossbeam_channel::Sender;
 use crate::{
     video::{
         sgbpalettes::*,
-        VideoDevice
+        VideoDevice,
+        PixelFormat,
+        ColourCorrection,
+        Layers
     },
     audio::{
         AudioDevice,
@@ -16,11 +19,7 @@ use crate::{
     interrupt::InterruptFlags
 };
 
-use std::sync::{
-    Arc, Mutex
-};
-
-use super::cartridge::{Cartridge, ROMType};
+use super::cartridge::{Cartridge, ROMType, CameraSource};
 use super::{MemDevice, WriteableMem};
 
 pub struct MemBus {
@@ -48,27 +47,50 @@ pub struct MemBus {
     cgb_dma_len:        u16,
     cgb_dma_hblank_len: Option<u16>,
 
-    cgb_mode:           bool
+    cgb_mode:           bool,
+    sgb_mode:           bool
+}
+
+// Find the GB palette to use, and if the cart should run in CGB or SGB mode.
+fn select_palette(user_palette: &UserPalette, cart: &Cartridge) -> (SGBPalette, bool, bool) {
+    let palette = match user_palette {
+        UserPalette::Default => if let Some(cart_hash) = cart.cart_name_hash() {
+            lookup_sgb_palette(cart_hash.0, cart_hash.1)
+        } else {
+            BW_PALETTE
+        },
+        UserPalette::Greyscale => BW_PALETTE,
+        UserPalette::Classic => CLASSIC_PALETTE,
+        UserPalette::Compatibility(None) => lookup_compatibility_palette(cart.cart_name_hash()),
+        UserPalette::Compatibility(Some(preset)) => preset.palette(),
+        UserPalette::SuperGameBoy => BW_PALETTE,
+        UserPalette::Custom(palette) => *palette
+    };
+    let sgb_mode = *user_palette == UserPalette::SuperGameBoy;
+
+    let cgb_mode = match user_palette {
+        UserPalette::Default | UserPalette::Compatibility(_) => cart.cgb_cart(),
+        _ => false
+    };
+
+    (palette, cgb_mode, sgb_mode)
 }
 
 impl MemBus {
-    pub fn new(rom: ROMType, save_file: &str, user_palette: UserPalette) -> MemBus {
+    pub fn new(rom: ROMType, save_file: &str, user_palette: UserPalette, pixel_format: PixelFormat) -> MemBus {
         let cart = match Cartridge::new(rom, save_file) {
             Ok(r) => r,
             Err(s) => panic!("Could not construct ROM: {}", s),
         };
 
-        let palette = match user_palette {
-            UserPalette::Default => if let Some(cart_hash) = cart.cart_name_hash() {
-                lookup_sgb_palette(cart_hash.0, cart_hash.1)
-            } else {
-                BW_PALETTE
-            },
-            UserPalette::Greyscale => BW_PALETTE,
-            UserPalette::Classic => CLASSIC_PALETTE
-        };
+        let (palette, cgb_mode, sgb_mode) = select_palette(&user_palette, &cart);
 
-        let cgb_mode = (user_palette == UserPalette::Default) && cart.cgb_cart();
+        let mut video_device = VideoDevice::new(palette, cgb_mode, pixel_format);
+        let mut joypad = Joypad::new();
+        if sgb_mode {
+            video_device.enable_sgb();
+            joypad.enable_sgb();
+        }
 
         MemBus {
             cart:               cart,
@@ -79,10 +101,10 @@ impl MemBus {
             interrupt_flag:     InterruptFlags::default(),
             interrupt_enable:   InterruptFlags::default(),
 
-            video_device:       VideoDevice::new(palette, cgb_mode),
+            video_device,
             audio_device:       AudioDevice::new(),
             timer:              Timer::new(),
-            joypad:             Joypad::new(),
+            joypad,
 
             dma_addr:           0,
             dma_active:         false,
@@ -92,13 +114,12 @@ impl MemBus {
             cgb_dma_dst:        0x8FF0,
             cgb_dma_len:        0,
             cgb_dma_hblank_len: None,
-            cgb_mode:           cgb_mode
+            cgb_mode:           cgb_mode,
+            sgb_mode
         }
     }
 
-    pub fn frame(&mut self, frame: Arc<Mutex<[u8]>>) {
-        self.video_device.start_frame(frame);
-
+    pub fn frame(&mut self) {
         if self.joypad.check_interrupt() {
             self.interrupt_flag.insert(InterruptFlags::JOYPAD);
         }
@@ -108,10 +129,15 @@ impl MemBus {
         self.audio_device.enable_audio(sender);
     }
 
+    pub fn set_audio_recorder(&mut self, recorder: Option<Sender<SamplePacket>>) {
+        self.audio_device.set_recorder(recorder);
+    }
+
     // Clock memory: update timer and DMA transfers.
     // Return true if CGB DMA is active.
     pub fn clock(&mut self, cycles: u32) -> bool {
         self.audio_device.clock(cycles);
+        self.cart.clock(cycles);
 
         if self.timer.update(cycles) {
             self.interrupt_flag.insert(InterruptFlags::TIMER);
@@ -150,12 +176,12 @@ impl MemBus {
         self.interrupt_flag.remove(flag);
     }
 
-    pub fn set_button(&mut self, button: Buttons, val: bool) {
-        self.joypad.set_button(button, val);
+    pub fn set_button(&mut self, player: usize, button: Buttons, val: bool) {
+        self.joypad.set_button(player, button, val);
     }
 
-    pub fn set_direction(&mut self, direction: Directions, val: bool) {
-        self.joypad.set_direction(direction, val);
+    pub fn set_direction(&mut self, player: usize, direction: Directions, val: bool) {
+        self.joypad.set_direction(player, direction, val);
     }
 
     // Flush the battery-backed RAM to disk.
@@ -167,12 +193,78 @@ impl MemBus {
         self.cart.name()
     }
 
+    pub fn rom_error(&mut self) -> Option<String> {
+        self.cart.rom_error()
+    }
+
+    pub fn set_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        self.cart.set_camera_source(source);
+    }
+
+    pub fn enable_pixel_fifo(&mut self, enable: bool) {
+        self.video_device.enable_pixel_fifo(enable);
+    }
+
+    // Change the GB palette while running.
+    // Palettes that need a different mode (CGB or SGB) can't be switched to.
+    pub fn set_palette(&mut self, user_palette: UserPalette) -> Result<(), String> {
+        let (palette, cgb_mode, sgb_mode) = select_palette(&user_palette, &self.cart);
+        if (cgb_mode != self.cgb_mode) || (sgb_mode != self.sgb_mode) {
+            return Err("Can't switch to a palette that changes CGB or SGB mode while running".to_string());
+        }
+        self.video_device.set_palette(palette);
+        Ok(())
+    }
+
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.video_device.set_colour_correction(correction);
+    }
+
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.video_device.set_layers(layers);
+    }
+
+    pub fn set_object_visible(&mut self, index: usize, visible: bool) {
+        self.video_device.set_object_visible(index, visible);
+    }
+
+    pub fn enable_sgb_border(&mut self, enable: bool) {
+        self.video_device.enable_sgb_border(enable);
+    }
+
+    pub fn frame_size(&self) -> (usize, usize) {
+        self.video_device.frame_size()
+    }
+
+    pub fn output_frame(&self, output: &mut [u8]) {
+        self.video_device.output_frame(output);
+    }
+
     // See if the memory is in CGB mode.
     pub fn is_cgb(&self) -> bool {
         self.cgb_mode
     }
 }
 
+#[cfg(feature = "debug")]
+impl MemBus {
+    pub fn draw_tile_sheet(&self, palette: usize, output: &mut [u8]) {
+        self.video_device.draw_tile_sheet(palette, output);
+    }
+
+    pub fn draw_tile_map(&self, map_1: bool, output: &mut [u8]) {
+        self.video_device.draw_tile_map(map_1, output);
+    }
+
+    pub fn get_object_info(&self) -> Vec<crate::debug::ObjectInfo> {
+        self.video_device.get_object_info()
+    }
+
+    pub fn draw_palettes(&self, output: &mut [u8]) {
+        self.video_device.draw_palettes(output);
+    }
+}
+
 // Internal functions
 impl MemBus {
     // Direct memory access for object memory.
@@ -287,7 +379,7 @@ impl MemDevice for MemBus {
             0xFF47..=0xFF4B => self.video_device.read(loc),
             0xFF4F          => self.video_device.read(loc),
             0xFF55          => self.get_cgb_len(),
-            0xFF68..=0xFF6B => self.video_device.read(loc),
+            0xFF68..=0xFF6C => self.video_device.read(loc),
             0xFF70          => self.get_cgb_ram_bank(),
             0xFF80..=0xFFFE => self.high_ram.read(loc - 0xFF80),
             0xFFFF          => self.interrupt_enable.bits(),
@@ -305,7 +397,12 @@ impl MemDevice for MemBus {
             0xE000..=0xEFFF => self.ram.write(loc - 0xE000, val),
             0xF000..=0xFDFF => self.ram.write((loc - 0xF000) + self.cgb_ram_offset, val),
             0xFE00..=0xFE9F => self.video_device.write(loc, val),
-            0xFF00          => self.joypad.write(val),
+            0xFF00          => {
+                self.joypad.write(val);
+                if let Some(command) = self.joypad.sgb_command() {
+                    self.video_device.sgb_command(&command);
+                }
+            },
             0xFF03..=0xFF07 => self.timer.write(loc, val),
             0xFF0F          => self.interrupt_flag = InterruptFlags::from_bits_truncate(val),
             0xFF10..=0xFF3F => self.audio_device.write(loc, val),
@@ -317,7 +414,7 @@ impl MemDevice for MemBus {
             0xFF53          => self.set_cgb_dma_upper_dst(val),
             0xFF54          => self.set_cgb_dma_lower_dst(val),
             0xFF55          => self.start_cgb_dma(val),
-            0xFF68..=0xFF6B => self.video_device.write(loc, val),
+            0xFF68..=0xFF6C => self.video_device.write(loc, val),
             0xFF70          => self.set_cgb_ram_bank(val),
             0xFF80..=0xFFFE => self.high_ram.write(loc - 0xFF80, val),
             0xFFFF          => self.interrupt_enable = InterruptFlags::from_bits_truncate(val),
@@ -0,0 +1,125 @@
+// Loading ROMs from compressed archives.
+// Zip and gzip files are supported. The ROM is extracted into memory.
+// The sizes stored in the archive aren't trusted: extraction stops if the ROM is larger than any real ROM.
+
+use std::{
+    io::{
+        BufReader,
+        Read
+    },
+    fs::File,
+    path::Path
+};
+
+use flate2::read::GzDecoder;
+
+use super::MAX_ROM_SIZE;
+
+const ZIP_MAGIC: &[u8] = &[0x50, 0x4B, 0x03, 0x04];
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+
+const ROM_EXTENSIONS: &[&str] = &["gb", "gbc", "sgb"];
+
+// Extract a ROM from an archive.
+// If no entry name is given, the first Game Boy ROM found is used.
+pub fn load_archive(file_name: &str, entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let mut magic = [0_u8; 4];
+    File::open(file_name)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map_err(|e| format!("Couldn't read archive {}: {}", file_name, e))?;
+
+    if magic.starts_with(ZIP_MAGIC) {
+        load_zip(file_name, entry)
+    } else if magic.starts_with(GZIP_MAGIC) {
+        load_gzip(file_name, entry)
+    } else {
+        Err(format!("{} is not a zip or gzip archive", file_name))
+    }
+}
+
+fn load_zip(file_name: &str, entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let file = File::open(file_name).map_err(|e| e.to_string())?;
+    let mut archive = zip::ZipArchive::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+
+    let name = match entry {
+        Some(name) => name.to_string(),
+        None => (0..archive.len())
+            .filter_map(|i| archive.by_index(i).ok().map(|f| f.name().to_string()))
+            .find(|name| is_rom_name(name))
+            .ok_or_else(|| format!("Couldn't find a Game Boy ROM in {}", file_name))?,
+    };
+
+    let mut rom_file = archive.by_name(&name).map_err(|_| format!("Couldn't find {} in {}", name, file_name))?;
+    read_rom(&mut rom_file, file_name)
+}
+
+// A gzip file only holds one file. If an entry name is given, it must match the stored name.
+fn load_gzip(file_name: &str, entry: Option<&str>) -> Result<Vec<u8>, String> {
+    let file = File::open(file_name).map_err(|e| e.to_string())?;
+    let mut decoder = GzDecoder::new(BufReader::new(file));
+
+    let data = read_rom(&mut decoder, file_name)?;
+
+    if let Some(name) = entry {
+        let stored_name = decoder.header()
+            .and_then(|h| h.filename())
+            .map(|f| String::from_utf8_lossy(f).into_owned());
+        if stored_name.as_deref() != Some(name) {
+            return Err(format!("Couldn't find {} in {}", name, file_name));
+        }
+    }
+
+    Ok(data)
+}
+
+// Extract a ROM, up to the largest ROM size.
+fn read_rom(reader: impl Read, file_name: &str) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    reader.take((MAX_ROM_SIZE as u64) + 1).read_to_end(&mut data).map_err(|e| e.to_string())?;
+    if data.len() > MAX_ROM_SIZE {
+        return Err(format!("ROM in {} is larger than {} bytes", file_name, MAX_ROM_SIZE));
+    }
+    Ok(data)
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name).extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ROM_EXTENSIONS.iter().any(|rom_ext| ext.eq_ignore_ascii_case(rom_ext)))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::{
+        write::GzEncoder,
+        Compression
+    };
+
+    fn write_gzip(name: &str, size: usize) -> String {
+        let path = std::env::temp_dir().join(format!("rustboy-archive-{}-{}.gz", name, std::process::id()));
+        let mut encoder = GzEncoder::new(File::create(&path).unwrap(), Compression::fast());
+        let chunk = vec![0; 0x10000];
+        for _ in 0..(size / chunk.len()) {
+            encoder.write_all(&chunk).unwrap();
+        }
+        encoder.finish().unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn gzip() {
+        let path = write_gzip("rom", 0x10000);
+        assert_eq!(load_archive(&path, None).unwrap().len(), 0x10000);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn gzip_too_large() {
+        let path = write_gzip("bomb", MAX_ROM_SIZE + 0x10000);
+        assert!(load_archive(&path, None).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,231 @@
+// Game Boy Camera (Pocket Camera) mapper and M64282FP sensor.
+// The sensor registers are mapped to A000-A07F (mirrored up to BFFF) when RAM bank 0x10 is selected.
+    // A000: bit 0 start capture / busy. Bits 1-2 are stored but have no effect here.
+    // A001: bit 7 N (exclusive edge mode), bits 5-6 VH (edge direction), bits 0-4 gain.
+    // A002-A003: exposure time (A002 is the high byte).
+    // A004: bit 7 invert, bits 4-6 edge enhancement ratio, bits 0-3 output reference voltage.
+    // A005: zero point and output offset voltage.
+    // A006-A035: 4x4 dithering matrix, 3 threshold bytes per position.
+// A captured image is processed and written to RAM bank 0 at 0x100 as 16x14 2bpp tiles.
+
+pub const CAMERA_WIDTH: usize = 128;
+pub const CAMERA_HEIGHT: usize = 112;
+
+const NUM_REGS: usize = 0x36;
+const MATRIX_START: usize = 6;
+
+// Location of the processed image in RAM bank 0.
+pub const IMAGE_RAM_START: u16 = 0x100;
+pub const IMAGE_RAM_SIZE: usize = (CAMERA_WIDTH / 8) * (CAMERA_HEIGHT / 8) * 16;
+
+// Edge enhancement ratios (x4).
+const EDGE_RATIOS: [i32; 8] = [2, 3, 4, 5, 8, 12, 16, 20];
+
+// Something that can provide images to the camera.
+// Implementors fill a buffer of CAMERA_WIDTH x CAMERA_HEIGHT greyscale pixels, row by row.
+// 0 is black and 255 is white.
+pub trait CameraSource {
+    fn capture(&mut self, image: &mut [u8]);
+}
+
+// A single still image used for every capture.
+pub struct StillImage {
+    image: Vec<u8>
+}
+
+impl StillImage {
+    // Make a camera image from greyscale data of any size.
+    pub fn new(data: &[u8], width: usize, height: usize) -> Result<Self, String> {
+        if (width == 0) || (height == 0) {
+            return Err(format!("Invalid camera image size: {}x{}", width, height));
+        }
+        let required = width.checked_mul(height).ok_or_else(|| format!("Camera image too large: {}x{}", width, height))?;
+        if data.len() < required {
+            return Err(format!("Camera image data too short: expected {} bytes for {}x{}, got {}", required, width, height, data.len()));
+        }
+
+        let mut image = vec![0; CAMERA_WIDTH * CAMERA_HEIGHT];
+
+        for (y, row) in image.chunks_exact_mut(CAMERA_WIDTH).enumerate() {
+            let src_y = (y * height) / CAMERA_HEIGHT;
+            for (x, pixel) in row.iter_mut().enumerate() {
+                let src_x = (x * width) / CAMERA_WIDTH;
+                *pixel = data[(src_y * width) + src_x];
+            }
+        }
+
+        Ok(StillImage {
+            image
+        })
+    }
+}
+
+impl CameraSource for StillImage {
+    fn capture(&mut self, image: &mut [u8]) {
+        image.copy_from_slice(&self.image);
+    }
+}
+
+pub struct Camera {
+    regs:           [u8; NUM_REGS],
+    regs_selected:  bool,
+    ram_bank:       u8,
+
+    source:         Option<Box<dyn CameraSource>>,
+
+    // Capture in progress
+    capture_cycles: u32,
+    image:          Vec<u8>,
+}
+
+impl Camera {
+    pub fn new() -> Self {
+        Camera {
+            regs:           [0; NUM_REGS],
+            regs_selected:  false,
+            ram_bank:       0,
+
+            source:         None,
+
+            capture_cycles: 0,
+            image:          vec![0; IMAGE_RAM_SIZE],
+        }
+    }
+
+    pub fn set_source(&mut self, source: Box<dyn CameraSource>) {
+        self.source = Some(source);
+    }
+
+    // Select RAM bank or camera registers.
+    pub fn select_bank(&mut self, val: u8) {
+        self.regs_selected = test_bit!(val, 4);
+        if !self.regs_selected {
+            self.ram_bank = val & 0xF;
+        }
+    }
+
+    pub fn get_ram_bank(&self) -> u8 {
+        self.ram_bank
+    }
+
+    pub fn regs_selected(&self) -> bool {
+        self.regs_selected
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.capture_cycles > 0
+    }
+
+    pub fn read_reg(&self, loc: u16) -> u8 {
+        match loc & 0x7F {
+            0 => (self.regs[0] & 0x6) | if self.is_busy() {1} else {0},
+            _ => 0,
+        }
+    }
+
+    pub fn write_reg(&mut self, loc: u16, val: u8) {
+        let reg = (loc & 0x7F) as usize;
+        if reg == 0 {
+            self.regs[0] = val & 0x7;
+            if test_bit!(val, 0) && !self.is_busy() {
+                self.start_capture();
+            }
+        } else if reg < NUM_REGS {
+            self.regs[reg] = val;
+        }
+    }
+
+    // Advance the capture. Returns the processed tile data when it completes.
+    pub fn clock(&mut self, cycles: u32) -> Option<&[u8]> {
+        if self.capture_cycles > 0 {
+            self.capture_cycles = self.capture_cycles.saturating_sub(cycles);
+            if self.capture_cycles == 0 {
+                return Some(&self.image);
+            }
+        }
+        None
+    }
+}
+
+// Internal
+impl Camera {
+    fn exposure(&self) -> u32 {
+        make_16!(self.regs[2], self.regs[3]) as u32
+    }
+
+    fn start_capture(&mut self) {
+        let mut sensor = vec![0x80; CAMERA_WIDTH * CAMERA_HEIGHT];
+        if let Some(source) = &mut self.source {
+            source.capture(&mut sensor);
+        }
+
+        self.process(&sensor);
+
+        // Capture time is given in CPU cycles.
+        let n_cycles = if test_bit!(self.regs[1], 7) {0} else {512};
+        self.capture_cycles = (32446 + n_cycles + (16 * self.exposure())) * 4;
+    }
+
+    // Run the sensor pipeline: exposure, inversion, edge enhancement, then dithering into tiles.
+    fn process(&mut self, sensor: &[u8]) {
+        let exposure = self.exposure() as i32;
+        let invert = test_bit!(self.regs[4], 7);
+        let edge_ratio = EDGE_RATIOS[((self.regs[4] >> 4) & 0x7) as usize];
+        let n = test_bit!(self.regs[1], 7);
+        let vh = (self.regs[1] >> 5) & 0x3;
+
+        let exposed = sensor.iter().map(|p| {
+            let value = ((*p as i32) * exposure / 0x300).min(255);
+            if invert {255 - value} else {value}
+        }).collect::<Vec<_>>();
+
+        let pixel = |x: isize, y: isize| -> i32 {
+            let x = x.clamp(0, CAMERA_WIDTH as isize - 1) as usize;
+            let y = y.clamp(0, CAMERA_HEIGHT as isize - 1) as usize;
+            exposed[(y * CAMERA_WIDTH) + x]
+        };
+
+        for byte in self.image.iter_mut() {
+            *byte = 0;
+        }
+
+        for y in 0..CAMERA_HEIGHT {
+            for x in 0..CAMERA_WIDTH {
+                let (xi, yi) = (x as isize, y as isize);
+                let p = pixel(xi, yi);
+                let horizontal = (2 * p) - pixel(xi - 1, yi) - pixel(xi + 1, yi);
+                let vertical = (2 * p) - pixel(xi, yi - 1) - pixel(xi, yi + 1);
+                let edge = match (n, vh) {
+                    (_, 0)      => 0,
+                    (false, 1)  => horizontal,
+                    (false, 2)  => vertical,
+                    _           => horizontal + vertical,
+                };
+                let value = (p + ((edge * edge_ratio) / 4)).clamp(0, 255) as u8;
+
+                // Dither against the thresholds for this position.
+                let matrix_pos = MATRIX_START + ((((y % 4) * 4) + (x % 4)) * 3);
+                let thresholds = &self.regs[matrix_pos..(matrix_pos + 3)];
+                let shade = if value < thresholds[0] {
+                    3
+                } else if value < thresholds[1] {
+                    2
+                } else if value < thresholds[2] {
+                    1
+                } else {
+                    0
+                };
+
+                let tile = ((y / 8) * (CAMERA_WIDTH / 8)) + (x / 8);
+                let row = (tile * 16) + ((y % 8) * 2);
+                let bit = bit!(7 - (x % 8));
+                if test_bit!(shade, 0) {
+                    self.image[row] |= bit;
+                }
+                if test_bit!(shade, 1) {
+                    self.image[row + 1] |= bit;
+                }
+            }
+        }
+    }
+}
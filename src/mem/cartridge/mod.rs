@@ -3,16 +3,64 @@
 mod ram;
 mod rom;
 mod mbc1;
+mod camera;
+mod tama5;
+mod patch;
+mod archive;
 
 use ram::*;
 use rom::*;
 use mbc1::MBC1;
+use camera::Camera;
+use tama5::{
+    Tama5,
+    Tama5Op,
+    RTCTime
+};
+
+pub use rom::ROM;
+pub use camera::{
+    CameraSource,
+    StillImage,
+    CAMERA_WIDTH,
+    CAMERA_HEIGHT
+};
 
 use super::MemDevice;
 
+// Largest ROM that will be loaded into memory (8MB, the largest size in the cartridge header).
+const MAX_ROM_SIZE: usize = 0x80_0000;
+
 pub enum ROMType {
     File(String),
     Data(Vec<u8>),
+    Archive(String, Option<String>),    // Zip or gzip file, and optionally the name of the ROM inside.
+    Mapped(String),                     // Memory-mapped file.
+    Streamed(String, usize, bool),      // File streamed in the background, the max number of banks to keep loaded,
+                                        // and whether to wait for a bank that isn't loaded yet (see ROMStream).
+    Custom(Box<dyn ROM>),
+    Patched(Box<ROMType>, Vec<String>),  // Base ROM, and IPS/UPS/BPS patch files to apply in order.
+}
+
+impl ROMType {
+    // Load the whole ROM into memory.
+    fn load(self) -> Result<Vec<u8>, String> {
+        match self {
+            ROMType::File(file_name) => std::fs::read(&file_name).map_err(|e| e.to_string()),
+            ROMType::Data(data) => Ok(data),
+            ROMType::Mapped(file_name) |
+            ROMType::Streamed(file_name, _, _) => std::fs::read(&file_name).map_err(|e| e.to_string()),
+            ROMType::Custom(_) => Err("Can't patch a custom ROM source".to_string()),
+            ROMType::Archive(file_name, entry) => archive::load_archive(&file_name, entry.as_deref()),
+            ROMType::Patched(base, patches) => {
+                let mut data = base.load()?;
+                for patch_file in patches.iter() {
+                    data = patch::apply_patch_file(data, patch_file)?;
+                }
+                Ok(data)
+            },
+        }
+    }
 }
 
 // Cartridge Memory Bank type
@@ -22,6 +70,8 @@ enum MBC {
     _2,
     _3,
     _5(u16),
+    Camera(Camera),
+    Tama5(Tama5),
 }
 
 // Cartridge extra features
@@ -44,6 +94,10 @@ impl Cartridge {
         let rom = match rom_type {
             ROMType::File(file_name) => ROMFile::new(&file_name)? as Box<dyn ROM>,
             ROMType::Data(data) => ROMData::new(&data) as Box<dyn ROM>,
+            ROMType::Mapped(file_name) => ROMMapped::new(&file_name)? as Box<dyn ROM>,
+            ROMType::Streamed(file_name, max_banks, wait_on_miss) => ROMStream::new(&file_name, max_banks, wait_on_miss)? as Box<dyn ROM>,
+            ROMType::Custom(rom) => rom,
+            in_memory => ROMData::new(&in_memory.load()?) as Box<dyn ROM>,
         };
 
         let (bank_type, features) = match rom.read(0x147) {
@@ -56,11 +110,14 @@ impl Cartridge {
             0x13                => (MBC::_3,              CartFeatures::Battery),
             0x19 | 0x1A | 0x1C | 0x1D => (MBC::_5(0),     CartFeatures::None),
             0x1B | 0x1E         => (MBC::_5(0),           CartFeatures::Battery),
+            0xFC                => (MBC::Camera(Camera::new()), CartFeatures::Battery),
+            0xFD                => (MBC::Tama5(Tama5::new()), CartFeatures::Timer),
             _                   => (MBC::_0,              CartFeatures::None)
         };
 
         let ram_size = match (&bank_type, rom.read(0x149)) {
             (MBC::_2,_)     => 0x200,
+            (MBC::Tama5(_),_) => tama5::EEPROM_SIZE,
             (_,0x1)         => 0x800,
             (_,0x2)         => 0x2000,
             (_,0x3)         => 0x8000,
@@ -91,6 +148,33 @@ impl Cartridge {
         self.ram.flush();
     }
 
+    // Advance any cartridge hardware.
+    pub fn clock(&mut self, cycles: u32) {
+        self.rom.update();
+        if let MBC::Camera(ref mut camera) = self.mem_bank {
+            if let Some(image) = camera.clock(cycles) {
+                // Write the captured image into RAM bank 0.
+                self.ram.set_bank(0, 0);
+                for (i, byte) in image.iter().enumerate() {
+                    self.ram.write(camera::IMAGE_RAM_START + (i as u16), *byte);
+                }
+                self.ram.set_bank(camera.get_ram_bank(), 0);
+            }
+        }
+    }
+
+    // Set the image source for the Game Boy Camera.
+    pub fn set_camera_source(&mut self, source: Box<dyn CameraSource>) {
+        if let MBC::Camera(ref mut camera) = self.mem_bank {
+            camera.set_source(source);
+        }
+    }
+
+    // Take the first error found while loading the ROM in the background.
+    pub fn rom_error(&mut self) -> Option<String> {
+        self.rom.take_error()
+    }
+
     // Get the ROM name.
     pub fn name(&self) -> String {
         use std::str::FromStr;
@@ -124,21 +208,11 @@ impl Cartridge {
         } else {
             old_code == 0x01
         };
-        // Get hash.
+        // Get hash: the sum of the whole title area.
         if valid {
-            let mut title_loc = 0x0134;
-            let mut hash = 0_u16;
-            loop {
-                let byte = self.read(title_loc);
-                if byte == 0 {
-                    break;
-                } else {
-                    hash += byte as u16;
-                    title_loc += 1;
-                }
-            }
+            let hash = (0x0134..=0x0143).fold(0_u8, |hash, loc| hash.wrapping_add(self.read(loc)));
             let char_4 = self.read(0x0137);
-            Some((hash as u8, char_4))
+            Some((hash, char_4))
         } else {
             None
         }
@@ -164,24 +238,95 @@ impl Cartridge {
 
     #[inline]
     fn read_ram(&self, loc: u16) -> u8 {
-        if self.ram_enable {
-            self.ram.read(loc)
-        } else {
-            0
+        match self.mem_bank {
+            // Camera RAM can always be read, except during capture.
+            MBC::Camera(ref camera) => if camera.regs_selected() {
+                camera.read_reg(loc)
+            } else if camera.is_busy() {
+                0
+            } else {
+                self.ram.read(loc)
+            },
+            MBC::Tama5(ref tama5) => tama5.read(loc),
+            _ => if self.ram_enable {
+                self.ram.read(loc)
+            } else {
+                0
+            },
         }
     }
 
     #[inline]
     fn write_ram(&mut self, loc: u16, val: u8) {
-        if self.ram_enable {
-            match self.mem_bank {
+        if let MBC::Tama5(ref mut tama5) = self.mem_bank {
+            let op = tama5.write(loc, val);
+            self.tama5_op(op);
+            return;
+        }
+
+        match self.mem_bank {
+            MBC::Camera(ref mut camera) if camera.regs_selected() => camera.write_reg(loc, val),
+            _ if self.ram_enable => match self.mem_bank {
                 MBC::_2 => self.ram.write(loc, val & 0xF),
                 _ => self.ram.write(loc, val),
-            }
+            },
+            _ => {},
         }
     }
 }
 
+// TAMA5 commands. The EEPROM and RTC are stored in clock RAM.
+impl Cartridge {
+    fn tama5_op(&mut self, op: Tama5Op) {
+        let read_val = match op {
+            Tama5Op::None => None,
+            Tama5Op::SwapROMBank(bank) => {
+                self.swap_rom_bank(bank);
+                None
+            },
+            Tama5Op::WriteEEPROM(addr, val) => {
+                self.ram.write(addr, val);
+                None
+            },
+            Tama5Op::ReadEEPROM(addr) => Some(self.ram.read(addr)),
+            Tama5Op::WriteRTC(digit, val) => {
+                let mut time = self.read_rtc();
+                time.set_digit(digit, val);
+                self.write_rtc(&time);
+                None
+            },
+            Tama5Op::ReadRTC(digit) => Some(self.read_rtc().digit(digit)),
+        };
+
+        if let (Some(val), MBC::Tama5(ref mut tama5)) = (read_val, &mut self.mem_bank) {
+            tama5.set_read_value(val);
+        }
+    }
+
+    // Read a clock RAM register, then remap the RAM.
+    fn read_clock_reg(&mut self, reg: u8) -> u8 {
+        self.ram.set_bank(reg, 0x4000);
+        let val = self.ram.read(0);
+        self.ram.set_bank(0, 0x4000);
+        val
+    }
+
+    fn read_rtc(&mut self) -> RTCTime {
+        let days_lo = self.read_clock_reg(0xB);
+        let days_hi = self.read_clock_reg(0xC);
+        RTCTime {
+            seconds:    self.read_clock_reg(0x8),
+            minutes:    self.read_clock_reg(0x9),
+            hours:      self.read_clock_reg(0xA),
+            days:       make_16!(days_hi & 1, days_lo),
+        }
+    }
+
+    fn write_rtc(&mut self, time: &RTCTime) {
+        self.ram.write_clock(time);
+    }
+}
+
 impl MemDevice for Cartridge {
     fn read(&self, loc: u16) -> u8 {
         match loc {
@@ -245,6 +390,16 @@ impl MemDevice for Cartridge {
                     (0x4000..=0x5FFF, _)    => self.swap_ram_bank(val),
                     _ => {},
                 },
+                MBC::Camera(ref mut camera) => match loc {
+                    0x0000..=0x1FFF => self.ram_enable = (val & 0xF) == 0xA,
+                    0x2000..=0x3FFF => self.swap_rom_bank((val & 0x3F) as u16),
+                    0x4000..=0x5FFF => {
+                        camera.select_bank(val);
+                        let ram_bank = camera.get_ram_bank();
+                        self.swap_ram_bank(ram_bank);
+                    },
+                    _ => {},
+                },
                 _ => {},
             }
         }
@@ -0,0 +1,455 @@
+// ROM patching. Supports IPS, UPS and BPS patches.
+// Patches are applied in memory to the whole ROM before the cartridge is constructed.
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: usize = 0x454F46;
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+
+use super::MAX_ROM_SIZE;
+
+// Size of the checksums at the end of UPS and BPS patches.
+const FOOTER_SIZE: usize = 12;
+
+// Read a patch file and apply it.
+pub fn apply_patch_file(rom: Vec<u8>, file_name: &str) -> Result<Vec<u8>, String> {
+    let patch = std::fs::read(file_name).map_err(|e| format!("Couldn't read patch {}: {}", file_name, e))?;
+    apply_patch(rom, &patch).map_err(|e| format!("Couldn't apply patch {}: {}", file_name, e))
+}
+
+// Apply a patch, detecting the format from its header.
+pub fn apply_patch(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, String> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else {
+        Err("unknown patch format".to_string())
+    }
+}
+
+// Reads through the patch data.
+struct PatchReader<'a> {
+    data:   &'a [u8],
+    pos:    usize,
+    end:    usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], start: usize, end: usize) -> Self {
+        PatchReader {
+            data,
+            pos: start,
+            end,
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.end
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        if self.pos >= self.end {
+            return Err("unexpected end of patch".to_string());
+        }
+        let byte = self.data[self.pos];
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.end).ok_or("unexpected end of patch")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    // Big-endian integer, for IPS.
+    fn big_endian(&mut self, len: usize) -> Result<usize, String> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, b| (acc << 8) | (*b as usize)))
+    }
+
+    // Variable-length integer, for UPS and BPS.
+    fn number(&mut self) -> Result<usize, String> {
+        let mut num = 0_usize;
+        let mut shift = 1_usize;
+        loop {
+            let byte = self.byte()?;
+            num = num.checked_add(((byte & 0x7F) as usize) * shift).ok_or("invalid number in patch")?;
+            if test_bit!(byte, 7) {
+                return Ok(num);
+            }
+            shift = shift.checked_shl(7).ok_or("invalid number in patch")?;
+            num = num.checked_add(shift).ok_or("invalid number in patch")?;
+        }
+    }
+}
+
+fn apply_ips(mut rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, String> {
+    let mut reader = PatchReader::new(patch, IPS_MAGIC.len(), patch.len());
+
+    loop {
+        let offset = reader.big_endian(3)?;
+        if offset == IPS_EOF {
+            break;
+        }
+
+        let size = reader.big_endian(2)?;
+        let (size, data) = if size == 0 {
+            // Run-length encoded record.
+            let run = reader.big_endian(2)?;
+            let val = reader.byte()?;
+            (run, vec![val; run])
+        } else {
+            (size, reader.bytes(size)?.to_vec())
+        };
+
+        let end = checked_end(offset, size)?;
+        if rom.len() < end {
+            rom.resize(end, 0);
+        }
+        rom[offset..end].copy_from_slice(&data);
+    }
+
+    // Optional truncation.
+    if !reader.done() {
+        let size = reader.big_endian(3)?;
+        rom.truncate(size);
+    }
+
+    Ok(rom)
+}
+
+fn apply_ups(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    check_crc("base ROM", source_crc, &rom)?;
+
+    let mut reader = PatchReader::new(patch, UPS_MAGIC.len(), patch.len() - FOOTER_SIZE);
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    if source_size != rom.len() {
+        return Err(format!("base ROM is {} bytes, patch expects {} bytes", rom.len(), source_size));
+    }
+    check_target_size(target_size)?;
+
+    let mut target = rom;
+    target.resize(target_size, 0);
+
+    let mut out_pos = 0_usize;
+    while !reader.done() {
+        out_pos = out_pos.checked_add(reader.number()?).filter(|pos| *pos <= target_size).ok_or("patch offset out of range")?;
+        loop {
+            let byte = reader.byte()?;
+            if let Some(out) = target.get_mut(out_pos) {
+                *out ^= byte;
+            }
+            out_pos += 1;
+            if byte == 0 {
+                break;
+            }
+        }
+    }
+
+    check_crc("patched ROM", target_crc, &target)?;
+    Ok(target)
+}
+
+fn apply_bps(rom: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, String> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    check_crc("base ROM", source_crc, &rom)?;
+
+    let mut reader = PatchReader::new(patch, BPS_MAGIC.len(), patch.len() - FOOTER_SIZE);
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    if source_size != rom.len() {
+        return Err(format!("base ROM is {} bytes, patch expects {} bytes", rom.len(), source_size));
+    }
+    check_target_size(target_size)?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_rel = 0_usize;
+    let mut target_rel = 0_usize;
+
+    // Move a relative offset. It can't go below zero.
+    let relative = |reader: &mut PatchReader, rel: &mut usize| -> Result<(), String> {
+        let data = reader.number()?;
+        let offset = data >> 1;
+        *rel = if (data & 1) != 0 {rel.checked_sub(offset)} else {rel.checked_add(offset)}.ok_or("patch offset out of range")?;
+        Ok(())
+    };
+
+    while !reader.done() {
+        let data = reader.number()?;
+        let length = (data >> 2) + 1;
+        let target_end = target.len().checked_add(length).filter(|end| *end <= target_size).ok_or("patch writes past the end of the target")?;
+        match data & 3 {
+            // Source read
+            0 => {
+                let start = target.len();
+                let bytes = rom.get(start..target_end).ok_or("source read out of range")?;
+                target.extend_from_slice(bytes);
+            },
+            // Target read
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // Source copy
+            2 => {
+                relative(&mut reader, &mut source_rel)?;
+                let end = checked_end(source_rel, length)?;
+                let bytes = rom.get(source_rel..end).ok_or("source copy out of range")?;
+                target.extend_from_slice(bytes);
+                source_rel = end;
+            },
+            // Target copy, may overlap with the bytes being written.
+            _ => {
+                relative(&mut reader, &mut target_rel)?;
+                for _ in 0..length {
+                    let byte = *target.get(target_rel).ok_or("target copy out of range")?;
+                    target.push(byte);
+                    target_rel += 1;
+                }
+            },
+        }
+    }
+
+    if target.len() != target_size {
+        return Err(format!("patched ROM is {} bytes, expected {} bytes", target.len(), target_size));
+    }
+
+    check_crc("patched ROM", target_crc, &target)?;
+    Ok(target)
+}
+
+// Find the end of a range of data to patch.
+fn checked_end(start: usize, length: usize) -> Result<usize, String> {
+    start.checked_add(length).filter(|end| *end <= MAX_ROM_SIZE).ok_or_else(|| "patch offset out of range".to_string())
+}
+
+fn check_target_size(target_size: usize) -> Result<(), String> {
+    if target_size > MAX_ROM_SIZE {
+        Err(format!("patched ROM would be {} bytes, the largest ROM is {} bytes", target_size, MAX_ROM_SIZE))
+    } else {
+        Ok(())
+    }
+}
+
+// Check the patch's own checksum, and return the source and target checksums.
+fn check_footer(patch: &[u8]) -> Result<(u32, u32), String> {
+    if patch.len() < FOOTER_SIZE + 4 {
+        return Err("patch is too small".to_string());
+    }
+
+    let footer = &patch[(patch.len() - FOOTER_SIZE)..];
+    let read_u32 = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    let patch_crc = read_u32(&footer[8..12]);
+    check_crc("patch", patch_crc, &patch[..(patch.len() - 4)])?;
+
+    Ok((read_u32(&footer[0..4]), read_u32(&footer[4..8])))
+}
+
+fn check_crc(what: &str, expected: u32, data: &[u8]) -> Result<(), String> {
+    let crc = crc32(data);
+    if crc == expected {
+        Ok(())
+    } else {
+        Err(format!("{} checksum mismatch (expected {:08X}, found {:08X})", what, expected, crc))
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xFFFF_FFFF_u32, |crc, byte| {
+        (0..8).fold(crc ^ (*byte as u32), |crc, _| {
+            if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base_rom() -> Vec<u8> {
+        (0..64).map(|i| i as u8).collect()
+    }
+
+    fn patched_rom() -> Vec<u8> {
+        let mut rom = base_rom();
+        rom[4] = 0xAA;
+        rom[5] = 0xBB;
+        rom.extend_from_slice(&[0xCC, 0xDD]);
+        rom
+    }
+
+    fn push_number(patch: &mut Vec<u8>, mut num: usize) {
+        loop {
+            let byte = (num & 0x7F) as u8;
+            num >>= 7;
+            if num == 0 {
+                patch.push(byte | 0x80);
+                return;
+            }
+            patch.push(byte);
+            num -= 1;
+        }
+    }
+
+    fn push_footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        let patch_crc = crc32(patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+    }
+
+    fn ips_patch() -> Vec<u8> {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x02, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x40, 0x00, 0x02, 0xCC, 0xDD]);
+        patch.extend_from_slice(b"EOF");
+        patch
+    }
+
+    fn ups_patch(target: &[u8]) -> Vec<u8> {
+        let source = base_rom();
+        let mut patch = UPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        // XOR runs: 4 unchanged bytes, then 2 changed bytes.
+        push_number(&mut patch, 4);
+        patch.extend_from_slice(&[4 ^ 0xAA, 5 ^ 0xBB, 0]);
+        // Skip to the end of the source, then the new bytes.
+        push_number(&mut patch, source.len() - 7);
+        patch.extend_from_slice(&[0xCC, 0xDD, 0]);
+        push_footer(&mut patch, &source, target);
+        patch
+    }
+
+    fn bps_patch(target: &[u8]) -> Vec<u8> {
+        let source = base_rom();
+        let mut patch = BPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, target.len());
+        push_number(&mut patch, 0);
+        // Source read 4 bytes, target read 2 bytes, source copy the rest from offset 6.
+        push_number(&mut patch, 3 << 2);
+        push_number(&mut patch, (1 << 2) | 1);
+        patch.extend_from_slice(&[0xAA, 0xBB]);
+        push_number(&mut patch, ((source.len() - 6 - 1) << 2) | 2);
+        push_number(&mut patch, 6 << 1);
+        // Target read the new bytes.
+        push_number(&mut patch, (1 << 2) | 1);
+        patch.extend_from_slice(&[0xCC, 0xDD]);
+        push_footer(&mut patch, &source, target);
+        patch
+    }
+
+    #[test]
+    fn ips() {
+        assert_eq!(apply_patch(base_rom(), &ips_patch()), Ok(patched_rom()));
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let patch = ips_patch();
+        assert!(apply_patch(base_rom(), &patch[..(patch.len() - 5)]).is_err());
+    }
+
+    #[test]
+    fn ips_out_of_range() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0xFF, 0x00]);
+        patch.extend_from_slice(b"EOF");
+        assert!(apply_patch(base_rom(), &patch).is_err());
+    }
+
+    #[test]
+    fn ups() {
+        assert_eq!(apply_patch(base_rom(), &ups_patch(&patched_rom())), Ok(patched_rom()));
+    }
+
+    #[test]
+    fn ups_crc_mismatch() {
+        let mut wrong_target = patched_rom();
+        wrong_target[0] ^= 1;
+        assert!(apply_patch(base_rom(), &ups_patch(&wrong_target)).is_err());
+
+        let mut wrong_source = base_rom();
+        wrong_source[0] ^= 1;
+        assert!(apply_patch(wrong_source, &ups_patch(&patched_rom())).is_err());
+    }
+
+    #[test]
+    fn ups_truncated() {
+        let patch = ups_patch(&patched_rom());
+        assert!(apply_patch(base_rom(), &patch[..(patch.len() - 1)]).is_err());
+        assert!(apply_patch(base_rom(), &patch[..6]).is_err());
+    }
+
+    #[test]
+    fn ups_out_of_range() {
+        let source = base_rom();
+        let mut patch = UPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, usize::MAX / 2);
+        patch.push(0);
+        push_footer(&mut patch, &source, &source);
+        assert!(apply_patch(source, &patch).is_err());
+    }
+
+    #[test]
+    fn bps() {
+        assert_eq!(apply_patch(base_rom(), &bps_patch(&patched_rom())), Ok(patched_rom()));
+    }
+
+    #[test]
+    fn bps_crc_mismatch() {
+        let mut wrong_target = patched_rom();
+        wrong_target[0] ^= 1;
+        assert!(apply_patch(base_rom(), &bps_patch(&wrong_target)).is_err());
+
+        let mut patch = bps_patch(&patched_rom());
+        let last = patch.len() - 1;
+        patch[last] ^= 1;
+        assert!(apply_patch(base_rom(), &patch).is_err());
+    }
+
+    #[test]
+    fn bps_truncated() {
+        let patch = bps_patch(&patched_rom());
+        assert!(apply_patch(base_rom(), &patch[..(patch.len() - 1)]).is_err());
+        assert!(apply_patch(base_rom(), &patch[..6]).is_err());
+    }
+
+    #[test]
+    fn bps_negative_offset() {
+        let source = base_rom();
+        let mut patch = BPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, 0);
+        // Source copy from offset -1.
+        push_number(&mut patch, ((source.len() - 1) << 2) | 2);
+        push_number(&mut patch, (1 << 1) | 1);
+        push_footer(&mut patch, &source, &source);
+        assert!(apply_patch(source, &patch).is_err());
+    }
+
+    #[test]
+    fn bps_too_large() {
+        let source = base_rom();
+        let mut patch = BPS_MAGIC.to_vec();
+        push_number(&mut patch, source.len());
+        push_number(&mut patch, usize::MAX / 2);
+        push_number(&mut patch, 0);
+        push_footer(&mut patch, &source, &source);
+        assert!(apply_patch(source, &patch).is_err());
+    }
+}
@@ -19,10 +19,13 @@ use std::{
 };
 
 use crate::mem::MemDevice;
+use super::tama5::RTCTime;
 
 pub trait RAM: MemDevice {
     fn set_bank(&mut self, bank: u8, loc: u16);
     fn flush(&mut self) {}
+    // Set the real time clock, if there is one.
+    fn write_clock(&mut self, _time: &RTCTime) {}
 }
 
 // Banked RAM
@@ -202,6 +205,16 @@ impl ClockRAM {
     }
 }
 
+impl ClockRAM {
+    // Add the time since the last update to the clock registers.
+    fn update_clock(&mut self) {
+        let now = Utc::now();
+        update_times(&now.signed_duration_since(self.time), &mut self.microseconds, &mut self.seconds, &mut self.minutes, &mut self.hours, &mut self.days);
+
+        self.time = now;
+    }
+}
+
 impl MemDevice for ClockRAM {
     fn read(&self, loc: u16) -> u8 {
         use RamMap::*;
@@ -275,12 +288,20 @@ impl RAM for ClockRAM {
             };
         } else if bank == 1 { // Latch the clock.
             self.latch = !self.latch;
+            self.update_clock();
+        }
+    }
 
-            let now = Utc::now();
-            update_times(&now.signed_duration_since(self.time), &mut self.microseconds, &mut self.seconds, &mut self.minutes, &mut self.hours, &mut self.days);
+    fn write_clock(&mut self, time: &RTCTime) {
+        // Bring the clock up to date first, so the time that passed before the write is dropped along with the old time.
+        self.update_clock();
 
-            self.time = now;
-        }
+        self.microseconds = 0;
+        self.seconds = time.seconds;
+        self.minutes = time.minutes;
+        self.hours = time.hours;
+        self.days = time.days & 0x1FF;
+        self.dirty = true;
     }
 
     fn flush(&mut self) {
@@ -1,7 +1,11 @@
 // ROM sources.
 
 use std::{
-    collections::HashMap,
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque
+    },
     io::{
         BufReader,
         Read,
@@ -11,16 +15,38 @@ use std::{
     fs::File
 };
 
+use crossbeam_channel::{
+    unbounded,
+    Sender,
+    Receiver
+};
+
+use memmap2::Mmap;
+
+const BANK_SIZE: usize = 0x4000;
+const PREFETCH_BANKS: u16 = 2;  // Number of banks either side of the current bank to stream in.
+
+// A source of ROM data.
+// Bank 0 is always mapped to 0x0000-0x3FFF, and the bank set with set_bank is mapped to 0x4000-0x7FFF.
 pub trait ROM {
     fn read(&self, loc: u16) -> u8;
     fn set_bank(&mut self, bank: u16);
+    // Called regularly, for sources that load data in the background.
+    fn update(&mut self) {}
+    // Take the first error found while loading ROM data, if there was one.
+    fn take_error(&mut self) -> Option<String> {
+        None
+    }
 }
 
-// A local file.
+// A local file. Banks are loaded from the file the first time they are switched in, and kept.
+// A bank that can't be read is recorded as an error, and reads as 0xFF.
 pub struct ROMFile {
-    bank_0:         [u8; 0x4000],
+    bank_0:         [u8; BANK_SIZE],
     bank_cache:     HashMap<usize, Vec<u8>>,
     bank_offset:    usize,
+    num_banks:      usize,
+    error:          Option<String>,
 
     file:           BufReader<File>,
 }
@@ -28,18 +54,23 @@ pub struct ROMFile {
 impl ROMFile {
     pub fn new(file_name: &str) -> Result<Box<Self>, String> {
         let f = File::open(file_name).map_err(|e| e.to_string())?;
+        let len = f.metadata().map_err(|e| e.to_string())?.len() as usize;
 
         let mut reader = BufReader::new(f);
-        let mut buf = [0_u8; 0x4000];
+        let mut buf = [0_u8; BANK_SIZE];
         reader.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
         reader.read_exact(&mut buf).map_err(|e| e.to_string())?;
 
-        Ok(Box::new(ROMFile {
+        let mut rom = ROMFile {
             bank_0:         buf,
             bank_cache:     HashMap::new(),
             bank_offset:    0,
+            num_banks:      len / BANK_SIZE,
+            error:          None,
             file:           reader,
-        }))
+        };
+        rom.set_bank(1);
+        Ok(Box::new(rom))
     }
 }
 
@@ -47,26 +78,32 @@ impl ROM for ROMFile {
     fn read(&self, loc: u16) -> u8 {
         match loc {
             0x0..=0x3FFF    => self.bank_0[loc as usize],
-            0x4000..=0x7FFF => self.bank_cache.get(&self.bank_offset).expect("Bank not loaded!")[(loc - 0x4000) as usize],
+            0x4000..=0x7FFF => self.bank_cache.get(&self.bank_offset).map_or(0xFF, |bank| bank[(loc - 0x4000) as usize]),
             _ => unreachable!()
         }
     }
 
     fn set_bank(&mut self, bank: u16) {
-        self.bank_offset = (bank as usize) * 0x4000;
+        self.bank_offset = ((bank as usize) % self.num_banks) * BANK_SIZE;
 
-        if self.bank_cache.get(&self.bank_offset).is_none() {
-            let mut rom_bank = vec![0; 0x4000];
-
-            self.file.seek(SeekFrom::Start(self.bank_offset as u64))
-                .expect("Couldn't swap in bank");
-
-            self.file.read_exact(&mut rom_bank)
-                .expect(&format!("Couldn't swap in bank at pos {}-{}", self.bank_offset, self.bank_offset + 0x3FFF));
-
-            self.bank_cache.insert(self.bank_offset, rom_bank);
+        if !self.bank_cache.contains_key(&self.bank_offset) {
+            let mut rom_bank = vec![0; BANK_SIZE];
+            let result = self.file.seek(SeekFrom::Start(self.bank_offset as u64))
+                .and_then(|_| self.file.read_exact(&mut rom_bank));
+            match result {
+                Ok(_) => {
+                    self.bank_cache.insert(self.bank_offset, rom_bank);
+                },
+                Err(e) => if self.error.is_none() {
+                    self.error = Some(format!("Couldn't load bank at {:X}: {}", self.bank_offset, e));
+                },
+            }
         }
     }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
+    }
 }
 
 // A raw blob.
@@ -94,8 +131,297 @@ impl ROM for ROMData {
     }
 
     fn set_bank(&mut self, bank: u16) {
-        self.bank_offset = (bank as usize) * 0x4000;
+        let num_banks = (self.data.len() / BANK_SIZE).max(1);
+        self.bank_offset = ((bank as usize) % num_banks) * BANK_SIZE;
+    }
+}
+
+// A memory-mapped file. Bank switching is free, and the OS pages the data in and out.
+pub struct ROMMapped {
+    map:            Mmap,
+    bank_offset:    usize,
+}
+
+impl ROMMapped {
+    pub fn new(file_name: &str) -> Result<Box<Self>, String> {
+        let f = File::open(file_name).map_err(|e| e.to_string())?;
+
+        // The file must not be modified while it is mapped.
+        let map = unsafe { Mmap::map(&f) }.map_err(|e| e.to_string())?;
+        if map.len() < BANK_SIZE {
+            return Err(format!("ROM {} is too small", file_name));
+        }
+
+        Ok(Box::new(ROMMapped {
+            map,
+            bank_offset:    0,
+        }))
+    }
+}
+
+impl ROM for ROMMapped {
+    fn read(&self, loc: u16) -> u8 {
+        match loc {
+            0x0..=0x3FFF    => self.map[loc as usize],
+            0x4000..=0x7FFF => self.map[self.bank_offset + (loc - 0x4000) as usize],
+            _ => unreachable!()
+        }
+    }
+
+    fn set_bank(&mut self, bank: u16) {
+        let num_banks = self.map.len() / BANK_SIZE;
+        self.bank_offset = ((bank as usize) % num_banks) * BANK_SIZE;
+    }
+}
+
+// A file streamed in by a loader thread, holding a bounded number of banks.
+// All reads after bank 0 are done on the loader thread. The least recently used bank is dropped when the cache is full.
+// Banks either side of the current bank are prefetched in the background.
+// If a bank is switched in that hasn't been loaded yet and wait_on_miss is set, the CPU waits for the loader.
+// Otherwise the bank reads as 0xFF until it arrives, so switching banks never waits on the file.
+pub struct ROMStream {
+    bank_0:         Vec<u8>,
+    current:        Vec<u8>,
+    current_bank:   Option<u16>,
+    current_loaded: bool,   // False while the current bank is still being loaded.
+    num_banks:      u16,
+    wait_on_miss:   bool,
+
+    cache:          VecDeque<(u16, Vec<u8>)>,   // Most recently used at the front.
+    max_banks:      usize,
+    pending:        HashSet<u16>,
+    error:          Option<String>,
+
+    requests:       Sender<u16>,
+    loaded:         Receiver<(u16, Result<Vec<u8>, String>)>,
+}
+
+impl ROMStream {
+    // Stream a file, keeping up to max_banks switchable banks in memory.
+    pub fn new(file_name: &str, max_banks: usize, wait_on_miss: bool) -> Result<Box<Self>, String> {
+        let f = File::open(file_name).map_err(|e| e.to_string())?;
+        let len = f.metadata().map_err(|e| e.to_string())?.len() as usize;
+        if len < BANK_SIZE {
+            return Err(format!("ROM {} is too small", file_name));
+        }
+        let num_banks = (len / BANK_SIZE).min(u16::MAX as usize) as u16;
+
+        let mut reader = BufReader::new(f);
+        let mut bank_0 = vec![0; BANK_SIZE];
+        reader.read_exact(&mut bank_0).map_err(|e| e.to_string())?;
+
+        let (requests, recv_requests) = unbounded::<u16>();
+        let (send_loaded, loaded) = unbounded();
+
+        std::thread::spawn(move || {
+            while let Ok(bank) = recv_requests.recv() {
+                let mut data = vec![0; BANK_SIZE];
+                let result = reader.seek(SeekFrom::Start((bank as u64) * (BANK_SIZE as u64)))
+                    .and_then(|_| reader.read_exact(&mut data))
+                    .map(|_| data)
+                    .map_err(|e| format!("Couldn't load bank {}: {}", bank, e));
+                if send_loaded.send((bank, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut rom = ROMStream {
+            bank_0,
+            current:        vec![0xFF; BANK_SIZE],
+            current_bank:   None,
+            current_loaded: false,
+            num_banks,
+            wait_on_miss,
+
+            cache:          VecDeque::new(),
+            max_banks:      max_banks.max(1),
+            pending:        HashSet::new(),
+            error:          None,
+
+            requests,
+            loaded,
+        };
+
+        rom.set_bank(1);
+        Ok(Box::new(rom))
+    }
+}
+
+impl ROMStream {
+    fn is_loaded(&self, bank: u16) -> bool {
+        ((Some(bank) == self.current_bank) && self.current_loaded) || self.cache.iter().any(|(b, _)| *b == bank)
+    }
+
+    fn request(&mut self, bank: u16) {
+        let bank = bank % self.num_banks;
+        if !self.is_loaded(bank) && self.pending.insert(bank) && self.requests.send(bank).is_err() {
+            self.pending.remove(&bank);
+            self.set_error("ROM loader thread has stopped".to_string());
+        }
+    }
+
+    // Take in any banks that have finished loading.
+    fn receive_loaded(&mut self) {
+        while let Ok((bank, result)) = self.loaded.try_recv() {
+            self.receive(bank, result);
+        }
+    }
+
+    // Wait for a requested bank to finish loading.
+    fn wait_for(&mut self, bank: u16) {
+        while self.pending.contains(&bank) {
+            match self.loaded.recv() {
+                Ok((loaded_bank, result)) => self.receive(loaded_bank, result),
+                Err(_) => {
+                    self.pending.clear();
+                    self.set_error("ROM loader thread has stopped".to_string());
+                },
+            }
+        }
+    }
+
+    // Swap in a loaded bank if it is the current bank, otherwise add it to the cache.
+    // Banks that are already loaded are dropped.
+    fn receive(&mut self, bank: u16, result: Result<Vec<u8>, String>) {
+        self.pending.remove(&bank);
+        match result {
+            Ok(data) => if (Some(bank) == self.current_bank) && !self.current_loaded {
+                self.current = data;
+                self.current_loaded = true;
+            } else if !self.is_loaded(bank) {
+                self.cache_bank(bank, data);
+            },
+            Err(e) => self.set_error(e),
+        }
+    }
+
+    fn cache_bank(&mut self, bank: u16, data: Vec<u8>) {
+        self.cache.push_front((bank, data));
+        self.cache.truncate(self.max_banks);
+    }
+
+    fn take_cached(&mut self, bank: u16) -> Option<Vec<u8>> {
+        let i = self.cache.iter().position(|(b, _)| *b == bank)?;
+        self.cache.remove(i).map(|(_, data)| data)
+    }
+
+    // Keep the first error.
+    fn set_error(&mut self, error: String) {
+        if self.error.is_none() {
+            self.error = Some(error);
+        }
+    }
+}
+
+impl ROM for ROMStream {
+    fn read(&self, loc: u16) -> u8 {
+        match loc {
+            0x0..=0x3FFF    => self.bank_0[loc as usize],
+            0x4000..=0x7FFF => self.current[(loc - 0x4000) as usize],
+            _ => unreachable!()
+        }
+    }
+
+    fn set_bank(&mut self, bank: u16) {
+        let bank = bank % self.num_banks;
+        if Some(bank) == self.current_bank {
+            return;
+        }
+
+        self.receive_loaded();
+        let mut data = self.take_cached(bank);
+        if data.is_none() {
+            self.request(bank);
+            if self.wait_on_miss {
+                self.wait_for(bank);
+                data = self.take_cached(bank);
+            }
+        }
+
+        // Swap the new bank in and put the old one back in the cache.
+        let old_loaded = std::mem::replace(&mut self.current_loaded, data.is_some());
+        let old = std::mem::replace(&mut self.current, data.unwrap_or_else(|| vec![0xFF; BANK_SIZE]));
+        if let (Some(old_bank), true) = (self.current_bank, old_loaded) {
+            self.cache_bank(old_bank, old);
+        }
+        self.current_bank = Some(bank);
+
+        for distance in 1..=PREFETCH_BANKS {
+            self.request(bank.wrapping_add(distance));
+            self.request(bank.wrapping_sub(distance));
+        }
+    }
+
+    fn update(&mut self) {
+        if !self.current_loaded {
+            self.receive_loaded();
+        }
+    }
+
+    fn take_error(&mut self) -> Option<String> {
+        self.error.take()
     }
 }
 
-// TODO: remote loading.
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a ROM where every byte of each bank is the bank number.
+    fn write_rom(name: &str, num_banks: usize) -> String {
+        let path = std::env::temp_dir().join(format!("rustboy-rom-{}-{}.gb", name, std::process::id()));
+        let data = (0..num_banks).flat_map(|bank| vec![bank as u8; BANK_SIZE]).collect::<Vec<_>>();
+        std::fs::write(&path, data).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn file() {
+        let path = write_rom("file", 8);
+        let mut rom = ROMFile::new(&path).unwrap();
+        assert_eq!(rom.read(0x0000), 0);
+        assert_eq!(rom.read(0x4000), 1);
+        rom.set_bank(5);
+        assert_eq!(rom.read(0x7FFF), 5);
+        rom.set_bank(9);
+        assert_eq!(rom.read(0x4000), 1);
+        assert_eq!(rom.take_error(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stream_wait_on_miss() {
+        let path = write_rom("wait", 16);
+        let mut rom = ROMStream::new(&path, 2, true).unwrap();
+        assert_eq!(rom.read(0x0000), 0);
+        assert_eq!(rom.read(0x4000), 1);
+        for bank in [7, 3, 15, 8, 3, 1, 12] {
+            rom.set_bank(bank);
+            assert_eq!(rom.read(0x4000), bank as u8);
+            assert!(rom.cache.len() <= 2);
+            let mut banks = rom.cache.iter().map(|(b, _)| *b).collect::<Vec<_>>();
+            banks.sort_unstable();
+            banks.dedup();
+            assert_eq!(banks.len(), rom.cache.len());
+        }
+        assert_eq!(rom.take_error(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn stream_no_wait() {
+        let path = write_rom("no-wait", 16);
+        let mut rom = ROMStream::new(&path, 4, false).unwrap();
+        rom.set_bank(10);
+        // The bank reads as 0xFF until it arrives.
+        let start = std::time::Instant::now();
+        while rom.read(0x4000) == 0xFF {
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            rom.update();
+        }
+        assert_eq!(rom.read(0x4000), 10);
+        assert_eq!(rom.take_error(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,149 @@
+// Bandai TAMA5 mapper.
+// All access goes through a nibble-wide register window in cart RAM space:
+    // A001: select register.
+    // A000: write low nibble to selected register, or read from it.
+// Registers:
+    // 0-1: ROM bank low and high nibble.
+    // 4-5: value to write, low and high nibble.
+    // 6: bit 0 is the high bit of the address, bits 1-3 select the command.
+    // 7: low nibble of the address. Writing this performs the command.
+    // A: reads as ready.
+    // C-D: result of the last read command, low and high nibble.
+// Commands:
+    // 0: write to EEPROM, 1: read from EEPROM, 2: write to RTC, 3: read from RTC.
+// The EEPROM is 32 bytes. RTC registers are BCD digits:
+    // 0-1: seconds, 2-3: minutes, 4-5: hours, 6: day of week, 7-9: day counter.
+
+const NUM_REGS: usize = 8;
+
+const BANK_LO: usize = 0;
+const BANK_HI: usize = 1;
+const WRITE_LO: usize = 4;
+const WRITE_HI: usize = 5;
+const ADDR_HI: usize = 6;
+const ADDR_LO: usize = 7;
+
+const ACTIVE: u8 = 0xA;
+const READ_LO: u8 = 0xC;
+const READ_HI: u8 = 0xD;
+
+pub const EEPROM_SIZE: usize = 0x20;
+
+// Something the cartridge has to do after a register write.
+pub enum Tama5Op {
+    None,
+    SwapROMBank(u16),
+    WriteEEPROM(u16, u8),
+    ReadEEPROM(u16),
+    WriteRTC(u8, u8),
+    ReadRTC(u8),
+}
+
+pub struct Tama5 {
+    regs:       [u8; NUM_REGS],
+    reg_select: u8,
+    read_val:   u8,
+}
+
+impl Tama5 {
+    pub fn new() -> Self {
+        Tama5 {
+            regs:       [0; NUM_REGS],
+            reg_select: 0,
+            read_val:   0,
+        }
+    }
+
+    pub fn read(&self, loc: u16) -> u8 {
+        if (loc & 1) == 1 {
+            0xFF
+        } else {
+            match self.reg_select {
+                ACTIVE  => 0xF1,
+                READ_LO => (self.read_val & 0xF) | 0xF0,
+                READ_HI => (self.read_val >> 4) | 0xF0,
+                _       => 0xF0,
+            }
+        }
+    }
+
+    pub fn write(&mut self, loc: u16, val: u8) -> Tama5Op {
+        if (loc & 1) == 1 {
+            self.reg_select = val & 0xF;
+            return Tama5Op::None;
+        }
+
+        let reg = self.reg_select as usize;
+        if reg >= NUM_REGS {
+            return Tama5Op::None;
+        }
+        self.regs[reg] = val & 0xF;
+
+        match reg {
+            BANK_LO | BANK_HI => Tama5Op::SwapROMBank(((self.regs[BANK_HI] << 4) | self.regs[BANK_LO]) as u16),
+            ADDR_LO => {
+                let addr = (((self.regs[ADDR_HI] & 1) << 4) | self.regs[ADDR_LO]) as u16;
+                let out = (self.regs[WRITE_HI] << 4) | self.regs[WRITE_LO];
+                match self.regs[ADDR_HI] >> 1 {
+                    0 => Tama5Op::WriteEEPROM(addr, out),
+                    1 => Tama5Op::ReadEEPROM(addr),
+                    2 => Tama5Op::WriteRTC(self.regs[ADDR_LO], out & 0xF),
+                    3 => Tama5Op::ReadRTC(self.regs[ADDR_LO]),
+                    _ => Tama5Op::None,
+                }
+            },
+            _ => Tama5Op::None,
+        }
+    }
+
+    // Set the result of a read command.
+    pub fn set_read_value(&mut self, val: u8) {
+        self.read_val = val;
+    }
+}
+
+// RTC time, split into BCD digits for the TAMA5.
+pub struct RTCTime {
+    pub seconds:    u8,
+    pub minutes:    u8,
+    pub hours:      u8,
+    pub days:       u16,
+}
+
+impl RTCTime {
+    pub fn digit(&self, digit: u8) -> u8 {
+        match digit {
+            0 => self.seconds % 10,
+            1 => self.seconds / 10,
+            2 => self.minutes % 10,
+            3 => self.minutes / 10,
+            4 => self.hours % 10,
+            5 => self.hours / 10,
+            6 => (self.days % 7) as u8,
+            7 => (self.days % 10) as u8,
+            8 => ((self.days / 10) % 10) as u8,
+            9 => (self.days / 100) as u8,
+            _ => 0,
+        }
+    }
+
+    pub fn set_digit(&mut self, digit: u8, val: u8) {
+        let val = val.min(9);
+        match digit {
+            0 => self.seconds = ((self.seconds / 10) * 10) + val,
+            1 => self.seconds = (self.seconds % 10) + (val * 10),
+            2 => self.minutes = ((self.minutes / 10) * 10) + val,
+            3 => self.minutes = (self.minutes % 10) + (val * 10),
+            4 => self.hours = ((self.hours / 10) * 10) + val,
+            5 => self.hours = (self.hours % 10) + (val * 10),
+            7 => self.days = ((self.days / 10) * 10) + val as u16,
+            8 => self.days = ((self.days / 100) * 100) + (self.days % 10) + ((val as u16) * 10),
+            9 => self.days = (self.days % 100) + ((val as u16) * 100),
+            _ => {},
+        }
+        self.seconds %= 60;
+        self.minutes %= 60;
+        self.hours %= 24;
+        self.days %= 512;
+    }
+}
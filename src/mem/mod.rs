@@ -4,7 +4,14 @@ mod bus;
 mod cartridge;
 
 pub use bus::MemBus;
-pub use cartridge::ROMType;
+pub use cartridge::{
+    ROMType,
+    ROM,
+    CameraSource,
+    StillImage,
+    CAMERA_WIDTH,
+    CAMERA_HEIGHT
+};
 
 pub trait MemDevice {
     fn read(&self, loc: u16) -> u8;
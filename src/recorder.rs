@@ -0,0 +1,170 @@
+// Recording video and audio to file.
+// Video is written as uncompressed Y4M (4:4:4), at 60 frames per emulated second.
+// Audio is written as 16-bit stereo WAV, at the emulator's sample rate.
+// Both are timed by the emulated clock, so they stay in sync regardless of how fast the emulator runs.
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{
+        BufWriter,
+        Seek,
+        SeekFrom,
+        Write
+    }
+};
+
+use crossbeam_channel::Receiver;
+
+use crate::audio::{
+    SamplePacket,
+    INPUT_SAMPLE_RATE
+};
+use crate::video::{
+    PixelFormat,
+    read_pixel
+};
+
+const FRAME_RATE: usize = 60;
+const WAV_HEADER_SIZE: u32 = 44;
+const WAV_BYTES_PER_SAMPLE: u32 = 4;    // 2 channels, 16 bits each.
+const WAV_MAX_DATA_SIZE: u32 = u32::MAX - (WAV_HEADER_SIZE - 8);    // The RIFF size must fit in 32 bits.
+
+pub struct Recorder {
+    video:          BufWriter<File>,
+    audio:          BufWriter<File>,
+    samples:        Receiver<SamplePacket>,
+
+    width:          usize,
+    height:         usize,
+    format:         PixelFormat,
+    planes:         Vec<u8>,        // Y, Cb and Cr planes of the current frame.
+    audio_bytes:    u32,
+    error:          Option<String>, // Recording stops after the first error.
+    finished:       bool,
+}
+
+impl Recorder {
+    pub fn new(video_file: &str, audio_file: &str, width: usize, height: usize, format: PixelFormat, samples: Receiver<SamplePacket>) -> Result<Self, String> {
+        let video = File::create(video_file).map_err(|e| format!("Couldn't create {}: {}", video_file, e))?;
+        let audio = File::create(audio_file).map_err(|e| format!("Couldn't create {}: {}", audio_file, e))?;
+
+        let mut recorder = Recorder {
+            video:          BufWriter::new(video),
+            audio:          BufWriter::new(audio),
+            samples,
+
+            width,
+            height,
+            format,
+            planes:         vec![0; width * height * 3],
+            audio_bytes:    0,
+            error:          None,
+            finished:       false,
+        };
+
+        let y4m_header = format!("YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444\n", width, height, FRAME_RATE);
+        recorder.video.write_all(y4m_header.as_bytes()).map_err(|e| format!("Couldn't write video header: {}", e))?;
+        // The sizes are filled in when the recording is finished.
+        recorder.write_wav_header().map_err(|e| format!("Couldn't write audio header: {}", e))?;
+
+        Ok(recorder)
+    }
+
+    // Record a frame, and the audio produced since the last one.
+    // The frame must be the size given when the recording was started.
+    pub fn write_frame(&mut self, frame: &[u8], width: usize, height: usize) {
+        if self.error.is_some() {
+            return;
+        }
+        if (width != self.width) || (height != self.height) {
+            self.error = Some(format!("Frame size changed from {}x{} to {}x{} while recording", self.width, self.height, width, height));
+            return;
+        }
+        if let Err(e) = self.write_video(frame).and_then(|_| self.write_audio()) {
+            self.error = Some(format!("Couldn't write recording: {}", e));
+        }
+    }
+
+    // Finish the files. Returns the first error found while recording.
+    // The files are finished even if there was an error, so whatever was recorded before it can be used.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+
+        if self.error.is_none() {
+            if let Err(e) = self.write_audio() {
+                self.error = Some(format!("Couldn't write recording: {}", e));
+            }
+        }
+        let finished = self.video.flush()
+            .and_then(|_| self.audio.seek(SeekFrom::Start(0)))
+            .and_then(|_| self.write_wav_header())
+            .and_then(|_| self.audio.flush());
+
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => finished.map_err(|e| format!("Couldn't write recording: {}", e))
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// Internal
+impl Recorder {
+    // Convert to BT.601 YCbCr, with each plane written in turn.
+    fn write_video(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        let bytes_per_pixel = self.format.bytes_per_pixel();
+        let pixel_count = self.width * self.height;
+        let planes = &mut self.planes;
+        for (i, p) in frame.chunks_exact(bytes_per_pixel).enumerate() {
+            let colour = read_pixel(p, self.format).colour;
+            let (r, g, b) = (colour.r as i32, colour.g as i32, colour.b as i32);
+            planes[i] = (16 + (((66 * r) + (129 * g) + (25 * b) + 128) >> 8)) as u8;
+            planes[pixel_count + i] = (128 + (((-38 * r) - (74 * g) + (112 * b) + 128) >> 8)) as u8;
+            planes[(pixel_count * 2) + i] = (128 + (((112 * r) - (94 * g) - (18 * b) + 128) >> 8)) as u8;
+        }
+
+        self.video.write_all(b"FRAME\n")?;
+        self.video.write_all(&self.planes)
+    }
+
+    fn write_audio(&mut self) -> std::io::Result<()> {
+        let to_i16 = |s: f32| (s.clamp(-1.0, 1.0) * (i16::MAX as f32)) as i16;
+        for packet in self.samples.try_iter() {
+            let audio_bytes = u32::try_from(packet.len()).ok()
+                .and_then(|samples| samples.checked_mul(WAV_BYTES_PER_SAMPLE))
+                .and_then(|bytes| self.audio_bytes.checked_add(bytes))
+                .filter(|bytes| *bytes <= WAV_MAX_DATA_SIZE)
+                .ok_or_else(|| std::io::Error::other("audio reached the 4 GiB WAV size limit"))?;
+            for sample in packet.iter() {
+                self.audio.write_all(&to_i16(sample[0]).to_le_bytes())?;
+                self.audio.write_all(&to_i16(sample[1]).to_le_bytes())?;
+            }
+            self.audio_bytes = audio_bytes;
+        }
+        Ok(())
+    }
+
+    fn write_wav_header(&mut self) -> std::io::Result<()> {
+        let sample_rate = INPUT_SAMPLE_RATE as u32;
+        self.audio.write_all(b"RIFF")?;
+        self.audio.write_all(&(WAV_HEADER_SIZE - 8 + self.audio_bytes).to_le_bytes())?;
+        self.audio.write_all(b"WAVEfmt ")?;
+        self.audio.write_all(&16_u32.to_le_bytes())?;                                  // Format chunk size
+        self.audio.write_all(&1_u16.to_le_bytes())?;                                   // PCM
+        self.audio.write_all(&2_u16.to_le_bytes())?;                                   // Channels
+        self.audio.write_all(&sample_rate.to_le_bytes())?;
+        self.audio.write_all(&(sample_rate * WAV_BYTES_PER_SAMPLE).to_le_bytes())?;   // Bytes per second
+        self.audio.write_all(&(WAV_BYTES_PER_SAMPLE as u16).to_le_bytes())?;           // Block size
+        self.audio.write_all(&16_u16.to_le_bytes())?;                                  // Bits per sample
+        self.audio.write_all(b"data")?;
+        self.audio.write_all(&self.audio_bytes.to_le_bytes())
+    }
+}
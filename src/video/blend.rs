@@ -0,0 +1,90 @@
+// Frame blending, to emulate the slow response of the LCD.
+// This is applied to the output frame only, so it doesn't affect emulation.
+use super::PixelFormat;
+
+// How the previous frame is blended into the new one.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameBlend {
+    None,
+    Mix,                // Average of this frame and the last one.
+    Persistence(f32),   // Each output frame keeps this fraction (0-1) of the last output frame.
+}
+
+pub struct FrameBlender {
+    blend:      FrameBlend,
+    format:     PixelFormat,
+    previous:   Vec<u8>,    // Last frame from the emulator for Mix, or the last output for Persistence.
+}
+
+impl FrameBlender {
+    pub fn new(format: PixelFormat) -> Self {
+        FrameBlender {
+            blend:      FrameBlend::None,
+            format,
+            previous:   Vec::new(),
+        }
+    }
+
+    pub fn set_blend(&mut self, blend: FrameBlend) {
+        self.blend = blend;
+        self.previous.clear();
+    }
+
+    // Blend the frame with the previous one. Indexed frames are left alone.
+    pub fn apply(&mut self, frame: &mut [u8]) {
+        if (self.blend == FrameBlend::None) || (self.format == PixelFormat::Indexed) {
+            return;
+        }
+
+        // Start again if there is no previous frame, or if the frame size changed.
+        if self.previous.len() != frame.len() {
+            self.previous = frame.to_vec();
+            return;
+        }
+
+        let (blend, format) = (self.blend, self.format);
+        if let FrameBlend::Mix = blend {
+            // Keep the unblended frame for next time.
+            for (out, prev) in frame.chunks_exact_mut(2).zip(self.previous.chunks_exact_mut(2)) {
+                let current = [out[0], out[1]];
+                blend_pixel_bytes(out, prev, blend, format);
+                prev.copy_from_slice(&current);
+            }
+        } else {
+            for (out, prev) in frame.chunks_exact_mut(2).zip(self.previous.chunks_exact_mut(2)) {
+                blend_pixel_bytes(out, prev, blend, format);
+                prev.copy_from_slice(out);
+            }
+        }
+    }
+}
+
+// Blend 2 bytes: either a full RGB565 pixel, or 2 channels of a 4-byte pixel.
+fn blend_pixel_bytes(out: &mut [u8], prev: &[u8], blend: FrameBlend, format: PixelFormat) {
+    if format == PixelFormat::RGB565 {
+        let current = u16::from_ne_bytes([out[0], out[1]]);
+        let previous = u16::from_ne_bytes([prev[0], prev[1]]);
+        let channel = |shift: u16, mask: u16| {
+            let c = blend_channel(((current >> shift) & mask) as u8, ((previous >> shift) & mask) as u8, blend);
+            (c as u16) << shift
+        };
+        let blended = channel(11, 0x1F) | channel(5, 0x3F) | channel(0, 0x1F);
+        out.copy_from_slice(&blended.to_ne_bytes());
+    } else {
+        out[0] = blend_channel(out[0], prev[0], blend);
+        out[1] = blend_channel(out[1], prev[1], blend);
+    }
+}
+
+// Persistence rounds towards the current value, so old frames fade out completely.
+#[inline]
+fn blend_channel(current: u8, previous: u8, blend: FrameBlend) -> u8 {
+    match blend {
+        FrameBlend::None => current,
+        FrameBlend::Mix => ((current as u16 + previous as u16) / 2) as u8,
+        FrameBlend::Persistence(amount) => {
+            let diff = (previous as f32 - current as f32) * amount.clamp(0.0, 1.0);
+            (current as f32 + diff.trunc()) as u8
+        }
+    }
+}
@@ -0,0 +1,253 @@
+// Scaling filters, applied to the finished output frame.
+// Scale2x and Scale3x only compare pixels, so they work the same for all pixel formats.
+// XBR2x and LCDGrid mix colours, so indexed pixels are picked rather than blended.
+use super::{
+    Colour,
+    Pixel,
+    PixelFormat,
+    vram::{
+        read_pixel,
+        write_pixel
+    }
+};
+
+const GRID_BRIGHTNESS: u32 = 3;     // Out of 4.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ScaleFilter {
+    None,
+    Scale2x,
+    Scale3x,
+    XBR2x,          // Edge-detecting 2x scaling, with blended edges.
+    LCDGrid(usize)  // Scale by this amount (at least 2), with dark lines between pixels.
+}
+
+impl ScaleFilter {
+    // The amount each side of the frame is multiplied by.
+    pub fn scale(self) -> usize {
+        match self {
+            ScaleFilter::None => 1,
+            ScaleFilter::Scale2x => 2,
+            ScaleFilter::Scale3x => 3,
+            ScaleFilter::XBR2x => 2,
+            ScaleFilter::LCDGrid(scale) => scale.max(2),
+        }
+    }
+
+    // Scale the input frame into the output. The output must be scale() times the width and height.
+    pub fn apply(self, input: &[u8], width: usize, height: usize, output: &mut [u8], format: PixelFormat) {
+        let frame = Frame {
+            data:   input,
+            width,
+            height,
+            bpp:    format.bytes_per_pixel(),
+        };
+        match self {
+            ScaleFilter::None => output.copy_from_slice(input),
+            ScaleFilter::Scale2x => scale_2x(&frame, output),
+            ScaleFilter::Scale3x => scale_3x(&frame, output),
+            ScaleFilter::XBR2x => xbr_2x(&frame, output, format),
+            ScaleFilter::LCDGrid(_) => lcd_grid(&frame, output, format, self.scale()),
+        }
+    }
+}
+
+// Input frame, with coordinates clamped to the edges.
+struct Frame<'a> {
+    data:   &'a [u8],
+    width:  usize,
+    height: usize,
+    bpp:    usize,
+}
+
+impl<'a> Frame<'a> {
+    #[inline]
+    fn get(&self, x: isize, y: isize) -> &'a [u8] {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        let index = ((y * self.width) + x) * self.bpp;
+        &self.data[index..(index + self.bpp)]
+    }
+}
+
+// Write a block of output pixels for an input pixel. The block is given row by row.
+#[inline]
+fn write_block(output: &mut [u8], frame: &Frame, x: usize, y: usize, scale: usize, block: &[&[u8]]) {
+    let out_width = frame.width * scale;
+    for (i, pixel) in block.iter().enumerate() {
+        let out_x = (x * scale) + (i % scale);
+        let out_y = (y * scale) + (i / scale);
+        let index = ((out_y * out_width) + out_x) * frame.bpp;
+        output[index..(index + frame.bpp)].copy_from_slice(pixel);
+    }
+}
+
+//   A
+// C P B
+//   D
+fn scale_2x(frame: &Frame, output: &mut [u8]) {
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let (xi, yi) = (x as isize, y as isize);
+            let p = frame.get(xi, yi);
+            let a = frame.get(xi, yi - 1);
+            let b = frame.get(xi + 1, yi);
+            let c = frame.get(xi - 1, yi);
+            let d = frame.get(xi, yi + 1);
+
+            let block = if (a != d) && (c != b) {
+                [
+                    if c == a {a} else {p},
+                    if a == b {b} else {p},
+                    if d == c {c} else {p},
+                    if b == d {d} else {p},
+                ]
+            } else {
+                [p; 4]
+            };
+            write_block(output, frame, x, y, 2, &block);
+        }
+    }
+}
+
+// A B C
+// D E F
+// G H I
+fn scale_3x(frame: &Frame, output: &mut [u8]) {
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let (xi, yi) = (x as isize, y as isize);
+            let a = frame.get(xi - 1, yi - 1);
+            let b = frame.get(xi, yi - 1);
+            let c = frame.get(xi + 1, yi - 1);
+            let d = frame.get(xi - 1, yi);
+            let e = frame.get(xi, yi);
+            let f = frame.get(xi + 1, yi);
+            let g = frame.get(xi - 1, yi + 1);
+            let h = frame.get(xi, yi + 1);
+            let i = frame.get(xi + 1, yi + 1);
+
+            let block = if (b != h) && (d != f) {
+                [
+                    if d == b {d} else {e},
+                    if ((d == b) && (e != c)) || ((b == f) && (e != a)) {b} else {e},
+                    if b == f {f} else {e},
+                    if ((d == b) && (e != g)) || ((d == h) && (e != a)) {d} else {e},
+                    e,
+                    if ((b == f) && (e != i)) || ((h == f) && (e != c)) {f} else {e},
+                    if d == h {d} else {e},
+                    if ((d == h) && (e != i)) || ((h == f) && (e != g)) {h} else {e},
+                    if h == f {f} else {e},
+                ]
+            } else {
+                [e; 9]
+            };
+            write_block(output, frame, x, y, 3, &block);
+        }
+    }
+}
+
+// 2xBR: for each corner, compare the weight of edges in each diagonal direction.
+// If the edge runs across the corner, the corner is blended with the closer neighbour.
+// For the bottom-right corner:
+//    A  B  C
+//    D  E  F  F4
+//    G  H  I  I4
+//       H5 I5
+fn xbr_2x(frame: &Frame, output: &mut [u8], format: PixelFormat) {
+    let out_width = frame.width * 2;
+    let pixel_at = |x: isize, y: isize| read_pixel(frame.get(x, y), format);
+
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let e = pixel_at(x as isize, y as isize);
+            // Corners: bottom-right, bottom-left, top-left, top-right.
+            // Each is found by rotating the neighbourhood, so the same test works for all of them.
+            let corners = [(1, 1, 0), (0, 1, 1), (0, 0, 2), (1, 0, 3)];
+            for &(out_x, out_y, rotation) in corners.iter() {
+                let at = |dx: isize, dy: isize| {
+                    let (rx, ry) = match rotation {
+                        0 => (dx, dy),
+                        1 => (-dy, dx),
+                        2 => (-dx, -dy),
+                        _ => (dy, -dx),
+                    };
+                    pixel_at(x as isize + rx, y as isize + ry)
+                };
+                let (b, c, d, f) = (at(0, -1), at(1, -1), at(-1, 0), at(1, 0));
+                let (g, h, i) = (at(-1, 1), at(0, 1), at(1, 1));
+                let (f4, i4, h5, i5) = (at(2, 0), at(2, 1), at(0, 2), at(1, 2));
+
+                // Edges running across the corner (/), against edges running towards it (\).
+                let wd1 = distance(e, c) + distance(e, g) + distance(i, f4) + distance(i, h5) + (4 * distance(h, f));
+                let wd2 = distance(h, d) + distance(h, i5) + distance(f, i4) + distance(f, b) + (4 * distance(e, i));
+
+                let pixel = if (wd1 < wd2) && (distance(e, f) != 0) && (distance(e, h) != 0) {
+                    let new = if distance(e, f) <= distance(e, h) {f} else {h};
+                    mix(e, new, format)
+                } else {
+                    e
+                };
+
+                let index = ((((y * 2) + out_y) * out_width) + (x * 2) + out_x) * frame.bpp;
+                write_pixel(&mut output[index..(index + frame.bpp)], pixel, format);
+            }
+        }
+    }
+}
+
+// Each pixel becomes a square with dark edges on the right and bottom.
+fn lcd_grid(frame: &Frame, output: &mut [u8], format: PixelFormat, scale: usize) {
+    let out_width = frame.width * scale;
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let pixel = read_pixel(frame.get(x as isize, y as isize), format);
+            let dark = if format == PixelFormat::Indexed {
+                pixel
+            } else {
+                let darken = |c: u8| ((c as u32 * GRID_BRIGHTNESS) / 4) as u8;
+                Pixel {
+                    colour: Colour::new(darken(pixel.colour.r), darken(pixel.colour.g), darken(pixel.colour.b)),
+                    index:  pixel.index
+                }
+            };
+
+            for out_y in 0..scale {
+                for out_x in 0..scale {
+                    let edge = (out_x == scale - 1) || (out_y == scale - 1);
+                    let index = ((((y * scale) + out_y) * out_width) + (x * scale) + out_x) * frame.bpp;
+                    write_pixel(&mut output[index..(index + frame.bpp)], if edge {dark} else {pixel}, format);
+                }
+            }
+        }
+    }
+}
+
+// Weighted YUV distance between colours.
+#[inline]
+fn distance(a: Pixel, b: Pixel) -> u32 {
+    let yuv = |c: Colour| {
+        let (r, g, b) = (c.r as i32, c.g as i32, c.b as i32);
+        let y = ((r * 299) + (g * 587) + (b * 114)) / 1000;
+        let u = ((b - y) * 493) / 1000;
+        let v = ((r - y) * 877) / 1000;
+        (y, u, v)
+    };
+    let (ya, ua, va) = yuv(a.colour);
+    let (yb, ub, vb) = yuv(b.colour);
+    ((48 * (ya - yb).abs()) + (7 * (ua - ub).abs()) + (6 * (va - vb).abs())) as u32
+}
+
+// Half way between two pixels. Indexed pixels can't be mixed, so the new one is used.
+#[inline]
+fn mix(a: Pixel, b: Pixel, format: PixelFormat) -> Pixel {
+    if format == PixelFormat::Indexed {
+        b
+    } else {
+        let avg = |x: u8, y: u8| ((x as u16 + y as u16) / 2) as u8;
+        Pixel {
+            colour: Colour::new(avg(a.colour.r, b.colour.r), avg(a.colour.g, b.colour.g), avg(a.colour.b, b.colour.b)),
+            index:  b.index
+        }
+    }
+}
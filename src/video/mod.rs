@@ -9,6 +9,10 @@ mod renderer_threads;
 mod renderer_nothreads;
 
 pub mod sgbpalettes;
+mod sgb;
+mod blend;
+mod filter;
+mod screenshot;
 
 // Video mode constants
 mod constants {
@@ -16,7 +20,12 @@ mod constants {
     pub const H_CYCLES: u32     = 456;              // Cycles per Line
     pub const MODE_1: u32       = 154 * H_CYCLES;   // Mode 1: V-Blank
     pub const MODE_2: u32       = 80;               // Mode 2: Reading OAM
-    pub const MODE_3: u32       = MODE_2 + 168;     // Mode 3: Reading OAM & VRAM
+    pub const MODE_3: u32       = 172;              // Mode 3: Reading OAM & VRAM (minimum length)
+    pub const WINDOW_PENALTY: u32   = 6;            // Extra mode 3 cycles when the window starts on the line
+    pub const SPRITE_PENALTY: u32   = 6;            // Extra mode 3 cycles for each sprite fetched
+    pub const LYC_DELAY: u32        = 4;            // Cycles after LY changes before LY=LYC is set
+    pub const LINE_153_LY_RESET: u32 = 4;           // Cycles into line 153 before LY reads 0
+    pub const LINE_153_LYC_0: u32   = 12;           // Cycles into line 153 before LY=LYC compares with 0
     pub const FRAME_CYCLE: u32  = 144 * H_CYCLES;   // Time spent cycling through modes 2,3 and 0 before V-Blank
 }
 
@@ -24,14 +33,29 @@ use crate::interrupt::InterruptFlags;
 use crate::mem::MemDevice;
 
 use sgbpalettes::SGBPalette;
+use sgb::{
+    SGBBorder,
+    SGB_FRAME_WIDTH,
+    SGB_FRAME_HEIGHT
+};
 use regs::VideoRegs;
 
 pub use types::{
     Colour,
-    PaletteColours
+    PaletteColours,
+    PixelFormat,
+    ColourCorrection,
+    Layers
 };
+use types::Pixel;
 
-use vram::VRAM;
+use vram::{
+    VRAM,
+    VRAMChange,
+    LogEntry,
+    PixelFifo
+};
+pub use vram::read_pixel;
 
 #[cfg(feature = "threads")]
 use renderer_threads::*;
@@ -39,12 +63,17 @@ use renderer_threads::*;
 #[cfg(not(feature = "threads"))]
 use renderer_nothreads::*;
 
-use std::sync::{
-    Arc,
-    Mutex
+pub use sgbpalettes::{
+    UserPalette,
+    CompatibilityPreset
 };
 
-pub use sgbpalettes::UserPalette;
+pub use blend::{
+    FrameBlend,
+    FrameBlender
+};
+pub use filter::ScaleFilter;
+pub use screenshot::Screenshot;
 
 // Modes
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -67,10 +96,15 @@ impl From<u8> for Mode {
 }
 
 pub struct VideoDevice {
-    vram:           Arc<Mutex<VRAM>>,
+    vram:           VRAM,               // The CPU's copy of VRAM. The renderer has its own copy.
+    log:            Vec<LogEntry>,      // Changes and lines for the frame, not yet sent to the renderer.
     regs:           VideoRegs,
 
     renderer:       Renderer,
+    pixel_fifo:     Option<PixelFifo>,  // If set, lines are drawn dot-by-dot during mode 3.
+    border:         Option<SGBBorder>,  // Copy of the SGB border from the renderer. Set in SGB mode.
+    sgb_border:     bool,               // If set, the output frame includes the SGB border.
+    pixel_format:   PixelFormat,
 
     // CGB things
     cgb_mode:       bool,
@@ -78,20 +112,28 @@ pub struct VideoDevice {
 
     // Misc
     cycle_count:    u32,
+    line_cycle:     u32,
+    mode_3_end:     u32,    // Line cycle that mode 3 ends on for the current line.
+    stat_line:      bool,   // Combined state of STAT interrupt sources.
+    stat_write_int: bool,   // Spurious STAT interrupt from writing to STAT on DMG.
 }
 
 impl VideoDevice {
-    pub fn new(palette: SGBPalette, cgb_mode: bool) -> Self {
-        let vram = Arc::new(Mutex::new(VRAM::new(palette, cgb_mode)));
-
+    pub fn new(palette: SGBPalette, cgb_mode: bool, pixel_format: PixelFormat) -> Self {
+        use self::constants::*;
         // Spin off video thread.
-        let renderer = Renderer::new(vram.clone());
+        let renderer = Renderer::new(VRAM::new(palette, cgb_mode, pixel_format));
 
         VideoDevice {
-            vram:           vram,
-            regs:           VideoRegs::new(),
+            vram:           VRAM::new(palette, cgb_mode, pixel_format),
+            log:            Vec::new(),
+            regs:           VideoRegs::new(cgb_mode),
 
             renderer:       renderer,
+            pixel_fifo:     None,
+            border:         None,
+            sgb_border:     false,
+            pixel_format,
 
             // CGB things
             cgb_mode:       cgb_mode,
@@ -99,12 +141,77 @@ impl VideoDevice {
 
             // Misc
             cycle_count:    0,
+            line_cycle:     0,
+            mode_3_end:     MODE_2 + MODE_3,
+            stat_line:      false,
+            stat_write_int: false,
+        }
+    }
+
+    // Use the pixel FIFO renderer instead of drawing whole lines at the start of mode 3.
+    // This is slower but handles mid-line register writes.
+    pub fn enable_pixel_fifo(&mut self, enable: bool) {
+        self.pixel_fifo = if enable {Some(PixelFifo::new())} else {None};
+    }
+
+    // Colour the screen using SGB commands.
+    pub fn enable_sgb(&mut self) {
+        self.log_change(VRAMChange::EnableSGB);
+        self.border = Some(SGBBorder::new());
+        self.sgb_border = true;
+    }
+
+    // Choose between outputting the SGB border around the screen, or just the screen.
+    // Has no effect outside of SGB mode.
+    pub fn enable_sgb_border(&mut self, enable: bool) {
+        self.sgb_border = enable;
+    }
+
+    // Width and height of the output frame.
+    pub fn frame_size(&self) -> (usize, usize) {
+        if self.has_sgb_border() {
+            (SGB_FRAME_WIDTH, SGB_FRAME_HEIGHT)
+        } else {
+            (160, 144)
+        }
+    }
+
+    // Write the last finished screen to the output frame, adding the SGB border if needed.
+    // The output must be exactly frame_size() pixels of the pixel format.
+    pub fn output_frame(&self, output: &mut [u8]) {
+        let (width, height) = self.frame_size();
+        assert_eq!(output.len(), width * height * self.pixel_format.bytes_per_pixel(), "output frame size doesn't match frame_size()");
+        let screen = self.renderer.frame();
+        match &self.border {
+            Some(border) if self.sgb_border => border.draw(&screen, output, self.pixel_format),
+            _ => output.copy_from_slice(&screen[..output.len()]),
         }
     }
 
-    // Drawing for a single frame.
-    pub fn start_frame(&mut self, render_target: RenderTarget) {
-        self.renderer.start_frame(render_target);
+    // Change the GB palette colours. The palette registers are kept.
+    pub fn set_palette(&mut self, palette: SGBPalette) {
+        self.vram.palettes.set_colours(palette);
+        self.log_change(VRAMChange::Palette(palette));
+    }
+
+    // Set the colour correction for CGB palettes.
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.vram.colour_palettes.set_colour_correction(correction);
+        self.log_change(VRAMChange::ColourCorrection(correction));
+    }
+
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.vram.set_layers(layers);
+        self.log_change(VRAMChange::Layers(layers));
+    }
+
+    pub fn set_object_visible(&mut self, index: usize, visible: bool) {
+        self.vram.set_object_visible(index, visible);
+        self.log_change(VRAMChange::ObjectVisible(index, visible));
+    }
+
+    pub fn sgb_command(&mut self, data: &[u8]) {
+        self.log_change(VRAMChange::SGBCommand(data.into()));
     }
 
     // Query to see if the video device is in H-Blank.
@@ -123,40 +230,82 @@ impl VideoDevice {
         if self.regs.is_display_enabled() {
             // First, calculate how many cycles into the horizontal line we are.
             let line_cycle = self.get_cycle_count() % H_CYCLES;
+            let new_line = line_cycle < self.line_cycle;
+            let last_line_cycle = std::mem::replace(&mut self.line_cycle, line_cycle);
             let mode = self.regs.read_mode();
 
-            let int = match mode {
-                Mode::_2 if line_cycle >= MODE_2 => self.update_mode(Mode::_3),
-                Mode::_3 if line_cycle >= MODE_3 => self.update_mode(Mode::_0),
+            let mut int = InterruptFlags::default();
+            match mode {
+                Mode::_2 if line_cycle >= MODE_2 => {
+                    self.regs.check_window_y();
+                    self.mode_3_end = MODE_2 + self.mode_3_cycles();
+                    self.update_mode(Mode::_3);
+                    self.step_pixel_fifo(line_cycle - MODE_2);
+                },
+                Mode::_3 => if self.pixel_fifo.is_some() {
+                    if self.step_pixel_fifo(line_cycle - last_line_cycle) {
+                        self.update_mode(Mode::_0);
+                    }
+                } else if line_cycle >= self.mode_3_end {
+                    self.update_mode(Mode::_0);
+                },
                 Mode::_0 if self.get_cycle_count() >= FRAME_CYCLE => {
                     self.regs.inc_lcdc_y();
-                    self.update_mode(Mode::_1) | InterruptFlags::V_BLANK
+                    self.regs.reset_window();
+                    self.update_mode(Mode::_1);
+                    self.end_frame();
+                    int.insert(InterruptFlags::V_BLANK);
                 },
-                Mode::_0 if line_cycle < MODE_3 => {
+                Mode::_0 if new_line => {
                     self.regs.inc_lcdc_y();
-                    self.update_mode(Mode::_2)
+                    self.update_mode(Mode::_2);
                 },
                 Mode::_1 => if self.get_cycle_count() >= MODE_1 {
-                    //self.renderer.end_frame();
                     self.regs.set_lcdc_y(0);
                     self.frame_cycle_reset();
-                    self.update_mode(Mode::_2)
+                    self.update_mode(Mode::_2);
                 } else {
-                    let new_ly = (self.get_cycle_count() / H_CYCLES) as u8;
+                    // LY reads 153 only briefly, before wrapping to 0 for the rest of the line.
+                    let new_ly = match (self.get_cycle_count() / H_CYCLES) as u8 {
+                        153 if line_cycle >= LINE_153_LY_RESET => 0,
+                        ly => ly,
+                    };
                     self.regs.set_lcdc_y(new_ly);
-                    InterruptFlags::default()
                 },
-                _ => InterruptFlags::default(),
-            };
+                _ => {},
+            }
 
-            (if int.contains(InterruptFlags::V_BLANK) {
-                true
+            // LY=LYC is compared a few cycles after LY changes.
+            // LY is already 0 at the start of line 0, so it doesn't change there.
+            // On line 153, LY reads 153 for the first 4 cycles, with no comparison made.
+            // LYC is then compared with 153 for 4 cycles after LY has wrapped to 0,
+            // and after a 4 cycle gap it is compared with 0.
+            // (Timing from "The Cycle-Accurate Game Boy Docs" by AntonioND.)
+            let ly = self.regs.read_lcdc_y();
+            let compare_ly = if (self.regs.read_mode() == Mode::_1) && ((self.get_cycle_count() / H_CYCLES) == 153) {
+                match line_cycle {
+                    c if c < LYC_DELAY => None,
+                    c if c < LYC_DELAY + LINE_153_LY_RESET => Some(153),
+                    c if c < LINE_153_LYC_0 => None,
+                    _ => Some(0),
+                }
+            } else if (ly == 0) || (line_cycle >= LYC_DELAY) {
+                Some(ly)
             } else {
-                false
-            }, int)
+                None
+            };
+            self.regs.set_coincidence(compare_ly);
+
+            if self.update_stat_line() {
+                int.insert(InterruptFlags::LCD_STAT);
+            }
+
+            (int.contains(InterruptFlags::V_BLANK), int)
         } else {
+            self.stat_line = false;
             let keep_cycling = if self.get_cycle_count() > MODE_1 {
                 self.frame_cycle_reset();
+                self.end_frame();
                 true
             } else {
                 false
@@ -165,49 +314,88 @@ impl VideoDevice {
         }
     }
 
-    // Update status reg, Trigger LCDC Status interrupt if necessary
-    fn update_mode(&mut self, mode: Mode) -> InterruptFlags {
-        use regs::LCDStatusFlags;
-        //let mem = self.mem.lock().unwrap();
-
+    // Update status reg, and draw if necessary.
+    fn update_mode(&mut self, mode: Mode) {
         self.regs.write_mode(mode);
-        let stat_flags = self.regs.read_flags();
 
-        if mode == Mode::_3 {
-            if self.cgb_mode {
-                self.renderer.draw_line_cgb(self.regs.clone());
-            } else {
-                self.renderer.draw_line_gb(self.regs.clone());
-            }
+        if mode == Mode::_0 {
+            let window_drawn = match &self.pixel_fifo {
+                Some(fifo) => fifo.drew_window(),
+                None => self.regs.is_window_on_line(),
+            };
+            self.regs.end_window_line(window_drawn);
         }
 
-        // Trigger STAT interrupt
-        if !stat_flags.is_empty() {
-            // LY Coincidence interrupt
-            if stat_flags.contains(LCDStatusFlags::COINCIDENCE_INT) {
-                if self.regs.compare_ly_equal() {
-                    return InterruptFlags::LCD_STAT;
-                }
-            } else if stat_flags.contains(LCDStatusFlags::OAM_INT) {
-                if mode == Mode::_2 {
-                    return InterruptFlags::LCD_STAT;
-                }
-            } else if stat_flags.contains(LCDStatusFlags::V_BLANK_INT) {
-                if mode == Mode::_1 {
-                    return InterruptFlags::LCD_STAT;
-                }
-            } else if stat_flags.contains(LCDStatusFlags::H_BLANK_INT) {
-                if mode == Mode::_0 {
-                    return InterruptFlags::LCD_STAT;
-                }
+        if let Some(fifo) = &mut self.pixel_fifo {
+            match mode {
+                Mode::_3 => fifo.start_line(&self.vram, &self.regs),
+                Mode::_0 => self.log.push(LogEntry::DrawLinePixels(self.regs.read_lcdc_y(), fifo.get_line().into())),
+                _ => {},
             }
+        } else if mode == Mode::_3 {
+            self.log.push(if self.cgb_mode {
+                LogEntry::DrawLineCGB(self.regs.clone())
+            } else {
+                LogEntry::DrawLineGB(self.regs.clone())
+            });
         }
+    }
+
+    // The STAT interrupt is requested on the rising edge of the STAT line,
+    // which is high if any of the enabled conditions are true.
+    // Returns true if an interrupt should be requested.
+    fn update_stat_line(&mut self) -> bool {
+        let stat_line = self.regs.stat_line(self.regs.read_flags());
+        let rising_edge = (stat_line && !self.stat_line) || self.stat_write_int;
+        self.stat_line = stat_line;
+        self.stat_write_int = false;
+        rising_edge
+    }
+}
+
+// VRAM viewers
+#[cfg(feature = "debug")]
+impl VideoDevice {
+    pub fn draw_tile_sheet(&self, palette: usize, output: &mut [u8]) {
+        self.vram.draw_tile_sheet(palette, self.cgb_mode, output);
+    }
+
+    pub fn draw_tile_map(&self, map_1: bool, output: &mut [u8]) {
+        self.vram.draw_tile_map(map_1, &self.regs, self.cgb_mode, output);
+    }
+
+    pub fn get_object_info(&self) -> Vec<crate::debug::ObjectInfo> {
+        self.vram.get_object_info(&self.regs, self.cgb_mode)
+    }
 
-        InterruptFlags::default()
+    pub fn draw_palettes(&self, output: &mut [u8]) {
+        self.vram.draw_palettes(self.cgb_mode, output);
     }
 }
 
 impl VideoDevice {
+    fn has_sgb_border(&self) -> bool {
+        self.sgb_border && self.border.is_some()
+    }
+
+    // Write to the CPU's copy of VRAM, and record it for the renderer.
+    fn write_vram(&mut self, loc: u16, val: u8) {
+        self.vram.write(loc, self.vram_bank, val);
+        self.log_change(VRAMChange::Write{loc, bank: self.vram_bank, val});
+    }
+
+    // Record a change for the renderer to replay.
+    fn log_change(&mut self, change: VRAMChange) {
+        self.log.push(LogEntry::Change(change));
+    }
+
+    // Hand the frame log to the renderer.
+    fn end_frame(&mut self) {
+        if let Some(border) = self.renderer.draw_frame(&mut self.log) {
+            self.border = Some(border);
+        }
+    }
+
     fn inc_cycle_count(&mut self, cycles: u32) {
         self.cycle_count += cycles;
     }
@@ -219,41 +407,65 @@ impl VideoDevice {
     fn get_cycle_count(&self) -> u32 {
         self.cycle_count
     }
+
+    // Find the length of mode 3 for the current line.
+    // The fetcher is delayed by fine scroll, the window starting, and fetching sprites.
+    fn mode_3_cycles(&self) -> u32 {
+        use self::constants::*;
+
+        let scroll_x = self.regs.scroll_x as u32;
+        let window = self.regs.is_window_on_line();
+        let mut cycles = MODE_3 + (scroll_x % 8);
+        if window {
+            cycles += WINDOW_PENALTY;
+        }
+
+        // Sprites cost 6 cycles, plus a delay if the background tile under them isn't fetched yet.
+        // Only the first sprite in each background tile pays this delay.
+        let sprites = self.vram.get_objects_for_line(self.regs.read_lcdc_y(), &self.regs);
+        let mut tiles_fetched = Vec::new();
+        for sprite in sprites.iter().filter(|s| s.x < 168) {
+            cycles += SPRITE_PENALTY;
+            if sprite.x == 0 {
+                cycles += 5;
+                continue;
+            }
+
+            // Sprite X is offset by 8, window X is offset by 7.
+            let window_x = self.regs.window_start_x();
+            let in_window = window && (sprite.x > window_x);
+            let tile_x = if in_window {
+                sprite.x - 1 - window_x
+            } else {
+                sprite.x.wrapping_sub(8).wrapping_add(self.regs.scroll_x)
+            } as u32;
+            let tile = (in_window, tile_x / 8);
+            if !tiles_fetched.contains(&tile) {
+                tiles_fetched.push(tile);
+                cycles += 5_u32.saturating_sub(tile_x % 8);
+            }
+        }
+
+        cycles
+    }
+
+    // Run the pixel FIFO for some dots. Returns true if the line is done.
+    fn step_pixel_fifo(&mut self, dots: u32) -> bool {
+        if let Some(fifo) = &mut self.pixel_fifo {
+            fifo.step(&self.vram, &self.regs, self.cgb_mode, dots)
+        } else {
+            false
+        }
+    }
 }
 
 impl MemDevice for VideoDevice {
     fn read(&self, loc: u16) -> u8 {
         match loc {
-            // Raw tile data
-            0x8000..=0x97FF if self.regs.can_access_vram() => {
-                let base = (loc - 0x8000) as usize + (self.vram_bank as usize * 0x1800);
-
-                if base % 2 == 0 {  // Lower bit
-                    self.vram.lock().unwrap().tile_mem.get_pixel_lower_row(base)
-                } else {            // Upper bit
-                    self.vram.lock().unwrap().tile_mem.get_pixel_upper_row(base)
-                }
-            },
-            // Background Map A
-            0x9800..=0x9BFF if self.regs.can_access_vram() => {
-                let index = (loc - 0x9800) as usize;
-                if self.vram_bank == 0 {
-                    self.vram.lock().unwrap().tile_map_0[index]
-                } else {
-                    self.vram.lock().unwrap().tile_attrs_0[index]
-                }
-            },
-            // Background Map B
-            0x9C00..=0x9FFF if self.regs.can_access_vram() => {
-                let index = (loc - 0x9C00) as usize;
-                if self.vram_bank == 0 {
-                    self.vram.lock().unwrap().tile_map_1[index]
-                } else {
-                    self.vram.lock().unwrap().tile_attrs_1[index]
-                }
-            },
+            // Tile data and maps
+            0x8000..=0x9FFF if self.regs.can_access_vram() => self.vram.read(loc, self.vram_bank),
             // Sprite data
-            0xFE00..=0xFE9F if self.regs.can_access_oam() => self.vram.lock().unwrap().object_mem.read(loc - 0xFE00),
+            0xFE00..=0xFE9F if self.regs.can_access_oam() => self.vram.read(loc, self.vram_bank),
             // Registers
             0xFF40 => self.regs.read_lcd_control(),
             0xFF41 => self.regs.read_status(),
@@ -261,86 +473,143 @@ impl MemDevice for VideoDevice {
             0xFF43 => self.regs.scroll_x,
             0xFF44 => self.regs.read_lcdc_y(),
             0xFF45 => self.regs.ly_compare,
-            0xFF47 => self.vram.lock().unwrap().palettes.read(0),
-            0xFF48 => self.vram.lock().unwrap().palettes.read(1),
-            0xFF49 => self.vram.lock().unwrap().palettes.read(2),
+            0xFF47..=0xFF49 => self.vram.read(loc, self.vram_bank),
             0xFF4A => self.regs.window_y,
             0xFF4B => self.regs.window_x,
             0xFF4F => self.vram_bank | 0xFE,
             // Colour palettes
-            0xFF68 => self.vram.lock().unwrap().colour_palettes.read_bg_index(),
-            0xFF69 => self.vram.lock().unwrap().colour_palettes.read_bg(),
-            0xFF6A => self.vram.lock().unwrap().colour_palettes.read_obj_index(),
-            0xFF6B => self.vram.lock().unwrap().colour_palettes.read_obj(),
+            0xFF68..=0xFF6B => self.vram.read(loc, self.vram_bank),
+            0xFF6C if self.cgb_mode => self.regs.read_obj_priority(),
             _ => 0xFF
         }
     }
 
     fn write(&mut self, loc: u16, val: u8) {
         match loc {
-            // Raw tile data
-            0x8000..=0x97FF if self.regs.can_access_vram() => {
-                let base = (loc - 0x8000) as usize + (self.vram_bank as usize * 0x1800);
-
-                let mut vram = self.vram.lock().unwrap();
-
-                if base % 2 == 0 {  // Lower bit
-                    vram.tile_mem.set_pixel_lower_row(base, val);
-                } else {            // Upper bit
-                    vram.tile_mem.set_pixel_upper_row(base, val);
-                }
-
-                vram.set_cache_0_dirty();
-                vram.set_cache_1_dirty();
-            },
-            // Background Map A
-            0x9800..=0x9BFF if self.regs.can_access_vram() => {
-                let index = (loc - 0x9800) as usize;
-                if self.vram_bank == 0 {
-                    self.vram.lock().unwrap().tile_map_0[index] = val;
-                } else {
-                    self.vram.lock().unwrap().tile_attrs_0[index] = val;
-                }
-
-                self.vram.lock().unwrap().set_cache_0_dirty();
-            },
-            // Background Map B
-            0x9C00..=0x9FFF if self.regs.can_access_vram() => {
-                let index = (loc - 0x9C00) as usize;
-                if self.vram_bank == 0 {
-                    self.vram.lock().unwrap().tile_map_1[index] = val;
-                } else {
-                    self.vram.lock().unwrap().tile_attrs_1[index] = val;
-                }
-                
-                self.vram.lock().unwrap().set_cache_1_dirty();
-            },
+            // Tile data and maps
+            0x8000..=0x9FFF if self.regs.can_access_vram() => self.write_vram(loc, val),
             // Sprite data
-            0xFE00..=0xFE9F if self.regs.can_access_oam() => self.vram.lock().unwrap().object_mem.write(loc - 0xFE00, val),
+            0xFE00..=0xFE9F if self.regs.can_access_oam() => self.write_vram(loc, val),
             0xFF40 => {
                 if self.regs.write_lcd_control(val) {
                     self.cycle_count = 0;
+                    self.line_cycle = 0;
+                }
+                self.log_change(VRAMChange::InvalidateMaps);
+            },
+            0xFF41 => {
+                // On DMG, all STAT sources are briefly enabled when it is written to.
+                if !self.cgb_mode && self.regs.is_display_enabled() && !self.stat_line {
+                    self.stat_write_int = self.regs.stat_line(regs::LCDStatusFlags::all());
                 }
-                self.vram.lock().unwrap().set_cache_0_dirty();
-                self.vram.lock().unwrap().set_cache_1_dirty();
+                self.regs.write_status(val);
             },
-            0xFF41 => self.regs.write_status(val),
             0xFF42 => self.regs.scroll_y = val,
             0xFF43 => self.regs.scroll_x = val,
             0xFF44 => self.regs.set_lcdc_y(0),
             0xFF45 => self.regs.ly_compare = val,
-            0xFF47 => self.vram.lock().unwrap().palettes.write(0, val),
-            0xFF48 => self.vram.lock().unwrap().palettes.write(1, val),
-            0xFF49 => self.vram.lock().unwrap().palettes.write(2, val),
+            0xFF47..=0xFF49 => self.write_vram(loc, val),
             0xFF4A => self.regs.window_y = val,
             0xFF4B => self.regs.window_x = val,
             0xFF4F => self.vram_bank = val & 1,
             // Colour palettes
-            0xFF68 => self.vram.lock().unwrap().colour_palettes.write_bg_index(val),
-            0xFF69 => self.vram.lock().unwrap().colour_palettes.write_bg(val),
-            0xFF6A => self.vram.lock().unwrap().colour_palettes.write_obj_index(val),
-            0xFF6B => self.vram.lock().unwrap().colour_palettes.write_obj(val),
+            0xFF68..=0xFF6B => self.write_vram(loc, val),
+            0xFF6C if self.cgb_mode => self.regs.write_obj_priority(val),
             _ => {}//unreachable!()
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::constants::*;
+
+    const STAT_COINCIDENCE: u8 = bit!(2);
+
+    fn new_device() -> VideoDevice {
+        VideoDevice::new(sgbpalettes::BW_PALETTE, false, PixelFormat::RGBA8888)
+    }
+
+    // Run for an M-cycle. Returns true if a STAT interrupt was requested.
+    fn step(video: &mut VideoDevice) -> bool {
+        video.video_mode(4).1.contains(InterruptFlags::LCD_STAT)
+    }
+
+    fn run_to(video: &mut VideoDevice, cycle: u32) {
+        while video.get_cycle_count() != cycle {
+            step(video);
+        }
+    }
+
+    // LY, the coincidence flag and STAT interrupts for each M-cycle at the start of line 153.
+    fn line_153(ly_compare: u8) -> Vec<(u8, bool, bool)> {
+        let mut video = new_device();
+        video.write(0xFF45, ly_compare);
+        video.write(0xFF41, 0x40);
+        run_to(&mut video, (153 * H_CYCLES) - 4);
+        (0..5).map(|_| {
+            let int = step(&mut video);
+            (video.read(0xFF44), (video.read(0xFF41) & STAT_COINCIDENCE) != 0, int)
+        }).collect()
+    }
+
+    #[test]
+    fn lyc_line_153() {
+        // LYC=153 matches for 4 cycles, after LY reads 0.
+        assert_eq!(line_153(153), [
+            (153, false, false),
+            (0, true, true),
+            (0, false, false),
+            (0, false, false),
+            (0, false, false),
+        ]);
+        // LYC=0 matches from 12 cycles in.
+        assert_eq!(line_153(0), [
+            (153, false, false),
+            (0, false, false),
+            (0, false, false),
+            (0, true, true),
+            (0, true, false),
+        ]);
+    }
+
+    #[test]
+    fn stat_blocking() {
+        // With H-Blank and OAM interrupts enabled, the STAT line stays high from mode 0 into mode 2,
+        // so only mode 0 requests an interrupt.
+        let mut video = new_device();
+        video.write(0xFF41, 0x28);
+        run_to(&mut video, H_CYCLES);
+        // Run for 4 lines.
+        for _ in 0..H_CYCLES {
+            let int = step(&mut video);
+            let mode = video.regs.read_mode();
+            let line_cycle = video.get_cycle_count() % H_CYCLES;
+            if int {
+                assert_eq!(mode, Mode::_0);
+            }
+            if line_cycle == 0 {
+                assert_eq!(mode, Mode::_2);
+                assert!(!int);
+            }
+        }
+
+        // With only the OAM interrupt, mode 2 requests one.
+        video.write(0xFF41, 0x20);
+        run_to(&mut video, (H_CYCLES * 10) - 4);
+        assert!(step(&mut video));
+        assert_eq!(video.regs.read_mode(), Mode::_2);
+    }
+
+    #[test]
+    fn stat_write_dmg() {
+        // Writing to STAT during H-Blank on DMG requests an interrupt, even with no sources enabled.
+        let mut video = new_device();
+        run_to(&mut video, H_CYCLES + MODE_2 + MODE_3 + 8);
+        assert_eq!(video.regs.read_mode(), Mode::_0);
+        video.write(0xFF41, 0x00);
+        assert!(step(&mut video));
+        assert!(!step(&mut video));
+    }
+}
@@ -77,10 +77,17 @@ pub struct VideoRegs {
     pub scroll_x:   u8,
     pub window_y:   u8,
     pub window_x:   u8,
+
+    // Window state
+    window_line:    u8,     // Internal line counter. Only advances on lines the window is drawn.
+    window_y_hit:   bool,   // Set once LY=WY in a frame.
+    window_wrapped: bool,   // The window was triggered at WX=166 on the last line.
+
+    x_priority:     bool,   // OPRI: sprite priority by X coordinate (DMG) or by OAM position (CGB).
 }
 
 impl VideoRegs {
-    pub fn new() -> Self {
+    pub fn new(cgb_mode: bool) -> Self {
         VideoRegs {
             lcd_control:    LCDControl::ENABLE,
             lcd_status:     LCDStatus::new(),
@@ -91,11 +98,13 @@ impl VideoRegs {
             scroll_x:       0,
             window_y:       0,
             window_x:       0,
-        }
-    }
 
-    pub fn compare_ly_equal(&self) -> bool {
-        self.lcdc_y == self.ly_compare
+            window_line:    0,
+            window_y_hit:   false,
+            window_wrapped: false,
+
+            x_priority:     !cgb_mode,
+        }
     }
 
     pub fn read_flags(&self) -> LCDStatusFlags {
@@ -120,11 +129,58 @@ impl VideoRegs {
         self.lcd_control.contains(LCDControl::WINDOW_DISPLAY_ENABLE)
     }
 
+    // True if the window will be drawn on the current line.
+    // WY is only checked at the start of each line, so the window appears once LY=WY,
+    // and stays until the end of the frame even if WY changes.
+    pub fn is_window_on_line(&self) -> bool {
+        self.get_window_enable() && self.window_y_hit && (self.window_start_x() <= 166)
+    }
+
+    // WX to draw the window from on this line.
+    // WX=166 shows the first column of the window in the last pixel. The window is then still active
+    // at the start of the next line, so it covers the whole line as if WX=7.
+    pub fn window_start_x(&self) -> u8 {
+        if self.window_wrapped {7} else {self.window_x}
+    }
+
+    // The row of the window to draw on this line.
+    pub fn window_line(&self) -> u8 {
+        self.window_line
+    }
+
+    // Call at the start of drawing each line.
+    pub fn check_window_y(&mut self) {
+        if self.lcdc_y == self.window_y {
+            self.window_y_hit = true;
+        }
+    }
+
+    // Call at the end of each line, with whether the window was drawn on it.
+    // The line counter only advances on lines with the window.
+    pub fn end_window_line(&mut self, window_drawn: bool) {
+        if window_drawn {
+            self.window_line = self.window_line.wrapping_add(1);
+        }
+        self.window_wrapped = window_drawn && (self.window_x == 166);
+    }
+
+    // Call at the start of each frame.
+    pub fn reset_window(&mut self) {
+        self.window_line = 0;
+        self.window_y_hit = false;
+        self.window_wrapped = false;
+    }
+
     // For sprites.
     pub fn is_large_sprites(&self) -> bool {
         self.lcd_control.contains(LCDControl::OBJ_SIZE)
     }
 
+    // True if sprites with lower X coordinates are drawn on top.
+    pub fn is_x_priority(&self) -> bool {
+        self.x_priority
+    }
+
     // True: range 0x8000 - 0x8FFF for bg & window raw tile data
     pub fn lo_tile_data_select(&self) -> bool {
         self.lcd_control.contains(LCDControl::TILE_DATA_SELECT)
@@ -144,12 +200,25 @@ impl VideoRegs {
                 
     pub fn inc_lcdc_y(&mut self) {
         self.lcdc_y += 1;
-        self.lcd_status.flags.set(LCDStatusFlags::COINCIDENCE_FLAG, self.lcdc_y == self.ly_compare);
     }
 
     pub fn set_lcdc_y(&mut self, val: u8) {
         self.lcdc_y = val;
-        self.lcd_status.flags.set(LCDStatusFlags::COINCIDENCE_FLAG, self.lcdc_y == self.ly_compare);
+    }
+
+    // Update the LY=LYC flag, comparing LYC with the line given. This is cleared while LY is changing (None).
+    pub fn set_coincidence(&mut self, compare_ly: Option<u8>) {
+        self.lcd_status.flags.set(LCDStatusFlags::COINCIDENCE_FLAG, compare_ly == Some(self.ly_compare));
+    }
+
+    // Find the state of the STAT interrupt line, with the provided interrupt sources enabled.
+    pub fn stat_line(&self, sources: LCDStatusFlags) -> bool {
+        let flags = self.lcd_status.flags;
+        let mode = self.lcd_status.read_mode();
+        (sources.contains(LCDStatusFlags::COINCIDENCE_INT) && flags.contains(LCDStatusFlags::COINCIDENCE_FLAG)) ||
+        (sources.contains(LCDStatusFlags::OAM_INT) && (mode == Mode::_2)) ||
+        (sources.contains(LCDStatusFlags::V_BLANK_INT) && (mode == Mode::_1)) ||
+        (sources.contains(LCDStatusFlags::H_BLANK_INT) && (mode == Mode::_0))
     }
 
     pub fn write_mode(&mut self, mode: Mode) {
@@ -184,6 +253,10 @@ impl VideoRegs {
     pub fn read_status(&self) -> u8 {
         self.lcd_status.read()
     }
+
+    pub fn read_obj_priority(&self) -> u8 {
+        if self.x_priority {0xFF} else {0xFE}
+    }
 }
 
 // Writing
@@ -198,6 +271,7 @@ impl VideoRegs {
         // Has display been toggled on/off?
         if is_display_enabled && !was_display_enabled {         // ON
             self.lcd_status.write_mode(Mode::_2);
+            self.reset_window();
             return true;
         } else if !is_display_enabled && was_display_enabled {  // OFF
             self.lcd_status.write_mode(Mode::_0);
@@ -210,4 +284,8 @@ impl VideoRegs {
     pub fn write_status(&mut self, val: u8) {
         self.lcd_status.write(val);
     }
+
+    pub fn write_obj_priority(&mut self, val: u8) {
+        self.x_priority = test_bit!(val, 0);
+    }
 }
\ No newline at end of file
@@ -1,48 +1,39 @@
-// Pixel renderer. Makes a texture of format R8G8B8A8Unorm
-use super::vram::VRAM;
-use super::regs::VideoRegs;
-
-use std::sync::{
-    Arc,
-    Mutex
+// Pixel renderer that draws on the CPU thread, with its own copy of VRAM.
+use super::vram::{
+    VRAM,
+    LogEntry
 };
+use super::sgb::SGBBorder;
 
-pub type RenderTarget = Arc<Mutex<[u8]>>;
+use std::ops::Deref;
 
-// Messages to send to the render thread.
-enum RendererMessage {
-    StartFrame(RenderTarget),   // Begin frame, and target the provided byte array.
-    DrawLineGB(VideoRegs),
-    DrawLineCGB(VideoRegs)
-}
+use crate::FRAME_SIZE_BYTES;
 
-// Renderer for video that spawns a thread to render on.
+// Renderer for video that draws the frame when it is handed over.
 pub struct Renderer {
-    mem:    Arc<Mutex<VRAM>>,
-    target: Option<RenderTarget>
+    vram:   VRAM,
+    target: Box<[u8]>,
 }
 
 impl Renderer {
-    pub fn new(mem: Arc<Mutex<VRAM>>) -> Self {
+    pub fn new(vram: VRAM) -> Self {
         Renderer {
-            mem:        mem,
-            target:     None,
+            vram,
+            target: vec![255; FRAME_SIZE_BYTES].into_boxed_slice(),
         }
     }
 
-    pub fn start_frame(&mut self, target: RenderTarget) {
-        self.target = Some(target);
-    }
-
-    pub fn draw_line_gb(&mut self, regs: VideoRegs) {
-        let mut mem = self.mem.lock().unwrap();
-        let mut t = self.target.as_ref().unwrap().lock().unwrap();
-        mem.draw_line_gb(&mut t, &regs);
+    // Draw a frame from the log. The log is left empty.
+    // Returns the SGB border if it changed.
+    pub fn draw_frame(&mut self, log: &mut Vec<LogEntry>) -> Option<SGBBorder> {
+        for entry in log.drain(..) {
+            self.vram.replay(entry, &mut self.target);
+        }
+        self.vram.sgb.as_mut().and_then(|sgb| sgb.take_border())
     }
 
-    pub fn draw_line_cgb(&mut self, regs: VideoRegs) {
-        let mut mem = self.mem.lock().unwrap();
-        let mut t = self.target.as_ref().unwrap().lock().unwrap();
-        mem.draw_line_cgb(&mut t, &regs);
+    // The last finished frame.
+    pub fn frame(&self) -> impl Deref<Target = [u8]> + '_ {
+        &*self.target
     }
-}
\ No newline at end of file
+}
@@ -1,10 +1,20 @@
-// Pixel renderer. Makes a texture of format R8G8B8A8Unorm
-use super::vram::VRAM;
-use super::regs::VideoRegs;
+// Pixel renderer that draws on its own thread, with its own copy of VRAM.
+// The frame log is sent once per frame, and replayed in order on the render thread.
+// Frames are pipelined: the renderer draws one frame while the CPU runs the next,
+// so the finished frame is one behind the CPU.
+// The renderer's VRAM is only touched by the render thread. Anything the CPU thread needs is sent back.
+use super::vram::{
+    VRAM,
+    LogEntry
+};
+use super::sgb::SGBBorder;
 
-use std::sync::{
-    Arc,
-    Mutex
+use std::{
+    ops::Deref,
+    sync::{
+        Arc,
+        Mutex
+    }
 };
 
 use crossbeam_channel::{
@@ -13,80 +23,96 @@ use crossbeam_channel::{
     Receiver
 };
 
+use crate::FRAME_SIZE_BYTES;
+
 pub type RenderTarget = Arc<Mutex<[u8]>>;
 
-// Messages to send to the render thread.
-enum RendererMessage {
-    StartFrame(RenderTarget),   // Begin frame, and target the provided byte array.
-    DrawLineGB(VideoRegs),
-    DrawLineCGB(VideoRegs)
+// A frame to draw: the log of changes and lines, and the target.
+// The target starts as a copy of the previous frame, so lines that aren't drawn are kept.
+struct FrameMessage {
+    target:     RenderTarget,
+    previous:   RenderTarget,
+    log:        Vec<LogEntry>,
+}
+
+// Reply when the frame is drawn: the emptied log to reuse, and the SGB border if it changed.
+struct FrameReply {
+    log:    Vec<LogEntry>,
+    border: Option<SGBBorder>,
 }
 
 // Renderer for video that spawns a thread to render on.
 pub struct Renderer {
-    sender:     Sender<RendererMessage>,
-    receiver:   Receiver<()>,
+    targets:    [RenderTarget; 2],
+    finished:   usize,          // Index of the target with the last finished frame.
+    drawing:    bool,           // A frame is being drawn to the other target.
+    sender:     Sender<FrameMessage>,
+    receiver:   Receiver<FrameReply>,
 }
 
 impl Renderer {
-    pub fn new(mem: Arc<Mutex<VRAM>>) -> Self {
-        let (send_msg, recv_msg) = unbounded();
+    pub fn new(mut vram: VRAM) -> Self {
+        let (send_msg, recv_msg) = unbounded::<FrameMessage>();
         let (send_reply, recv_reply) = unbounded();
 
         std::thread::spawn(move || {
-            use RendererMessage::*;
-            let mut target = None;
-
-            while let Ok(msg) = recv_msg.recv() {
-                match msg {
-                    StartFrame(data) => {
-                        target = Some(data);
-                    },
-                    DrawLineGB(regs) => {
-                        let mut mem = mem.lock().unwrap();
-                        let mut t = target.as_ref().unwrap().lock().unwrap();
-                        send_reply.send(()).unwrap();
-                        mem.draw_line_gb(&mut t, &regs);
-                    },
-                    DrawLineCGB(regs) => {
-                        let mut mem = mem.lock().unwrap();
-                        let mut t = target.as_ref().unwrap().lock().unwrap();
-                        send_reply.send(()).unwrap();
-                        mem.draw_line_cgb(&mut t, &regs);
+            while let Ok(mut frame) = recv_msg.recv() {
+                {
+                    let mut target = frame.target.lock().unwrap();
+                    target.copy_from_slice(&frame.previous.lock().unwrap());
+                    for entry in frame.log.drain(..) {
+                        vram.replay(entry, &mut target);
                     }
                 }
+                let border = vram.sgb.as_mut().and_then(|sgb| sgb.take_border());
+                if send_reply.send(FrameReply { log: frame.log, border }).is_err() {
+                    break;
+                }
             }
         });
 
         Renderer {
+            targets:    [new_target(), new_target()],
+            finished:   0,
+            drawing:    false,
             sender:     send_msg,
             receiver:   recv_reply,
         }
     }
 
-    pub fn start_frame(&mut self, target: RenderTarget) {
-        self.sender
-            .send(RendererMessage::StartFrame(target))
-            .expect("Couldn't send start frame message!");
-    }
+    // Start drawing a frame from the log, after waiting for the previous frame to finish.
+    // The log is swapped with the previous one, emptied.
+    // Returns the SGB border if it changed in the previous frame.
+    pub fn draw_frame(&mut self, log: &mut Vec<LogEntry>) -> Option<SGBBorder> {
+        let (spare, border) = if self.drawing {
+            let reply = self.receiver
+                .recv()
+                .expect("Couldn't finish frame!");
+            self.finished = 1 - self.finished;
+            (reply.log, reply.border)
+        } else {
+            (Vec::new(), None)
+        };
 
-    pub fn draw_line_gb(&mut self, regs: VideoRegs) {
+        let message = FrameMessage {
+            target:     self.targets[1 - self.finished].clone(),
+            previous:   self.targets[self.finished].clone(),
+            log:        std::mem::replace(log, spare),
+        };
         self.sender
-            .send(RendererMessage::DrawLineGB(regs))
-            .expect("Couldn't send draw line message!");
+            .send(message)
+            .expect("Couldn't send frame to renderer!");
+        self.drawing = true;
 
-        self.receiver
-            .recv()
-            .expect("GB");
+        border
     }
 
-    pub fn draw_line_cgb(&mut self, regs: VideoRegs) {
-        self.sender
-            .send(RendererMessage::DrawLineCGB(regs))
-            .expect("Couldn't send draw line message!");
-
-        self.receiver
-            .recv()
-            .expect("CGB");
+    // The last finished frame.
+    pub fn frame(&self) -> impl Deref<Target = [u8]> + '_ {
+        self.targets[self.finished].lock().unwrap()
     }
-}
\ No newline at end of file
+}
+
+fn new_target() -> RenderTarget {
+    Arc::new(Mutex::new([255; FRAME_SIZE_BYTES]))
+}
@@ -0,0 +1,61 @@
+// Screenshots of the output frame, and PNG encoding.
+use super::{
+    PixelFormat,
+    ScaleFilter,
+    vram::read_pixel
+};
+
+// A copy of the output frame, before blending and filtering.
+pub struct Screenshot {
+    pub width:  usize,
+    pub height: usize,
+    pub format: PixelFormat,
+    pub data:   Vec<u8>,        // Pixels in the format above.
+    pub filter: ScaleFilter,    // The filter that was active when the screenshot was taken.
+}
+
+impl Screenshot {
+    // Encode as an RGB PNG. If scaled is set, the filter is applied first.
+    // Indexed frames are saved as indexed PNGs, with a grey palette made from the shade (the bottom 2 bits of the index).
+    pub fn encode_png(&self, scaled: bool) -> Result<Vec<u8>, String> {
+        let filter = if scaled {self.filter} else {ScaleFilter::None};
+        let scale = filter.scale();
+        let (width, height) = (self.width * scale, self.height * scale);
+
+        let mut filtered = vec![0; width * height * self.format.bytes_per_pixel()];
+        filter.apply(&self.data, self.width, self.height, &mut filtered, self.format);
+
+        let mut png_data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png_data, width as u32, height as u32);
+        encoder.set_depth(png::BitDepth::Eight);
+        let image_data = if self.format == PixelFormat::Indexed {
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_palette(grey_palette());
+            filtered
+        } else {
+            encoder.set_color(png::ColorType::Rgb);
+            filtered.chunks_exact(self.format.bytes_per_pixel()).flat_map(|p| {
+                let colour = read_pixel(p, self.format).colour;
+                [colour.r, colour.g, colour.b]
+            }).collect::<Vec<_>>()
+        };
+        let mut writer = encoder.write_header().map_err(|e| format!("Couldn't write PNG header: {}", e))?;
+        writer.write_image_data(&image_data).map_err(|e| format!("Couldn't write PNG data: {}", e))?;
+        writer.finish().map_err(|e| format!("Couldn't finish PNG: {}", e))?;
+
+        Ok(png_data)
+    }
+
+    pub fn save_png(&self, file_name: &str, scaled: bool) -> Result<(), String> {
+        let png_data = self.encode_png(scaled)?;
+        std::fs::write(file_name, png_data).map_err(|e| format!("Couldn't write {}: {}", file_name, e))
+    }
+}
+
+// An RGB palette for all 256 indices. Shade 0 is white and shade 3 is black.
+fn grey_palette() -> Vec<u8> {
+    (0..=255_u8).flat_map(|index| {
+        let grey = 255 - ((index & 3) * 85);
+        [grey, grey, grey]
+    }).collect()
+}
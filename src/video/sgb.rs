@@ -0,0 +1,478 @@
+// Super Game Boy colouring.
+// The game sends commands through the joypad register, which are decoded there and handled here.
+// The screen is split into 20x18 blocks of 8x8 pixels. Each block is coloured with one of four palettes,
+// chosen by the attribute map. The GB shade of each pixel picks the colour from the palette.
+// Larger data (borders, system palettes, attribute files) is sent by displaying it on screen,
+// then sending a transfer command. The next frame is read back as 4KB of tile data.
+
+use super::{
+    Colour,
+    PaletteColours,
+    Pixel,
+    PixelFormat,
+    vram::write_pixel
+};
+
+pub const SGB_BLOCKS_X: usize = 20;
+pub const SGB_BLOCKS_Y: usize = 18;
+
+// Size of the output frame, with the border.
+pub const SGB_FRAME_WIDTH: usize = 256;
+pub const SGB_FRAME_HEIGHT: usize = 224;
+
+const SCREEN_WIDTH: usize = 160;
+const SCREEN_HEIGHT: usize = 144;
+// Position of the GB screen inside the border.
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+const NUM_SYSTEM_PALETTES: usize = 512;
+const NUM_ATTR_FILES: usize = 45;
+const ATTR_FILE_SIZE: usize = (SGB_BLOCKS_X * SGB_BLOCKS_Y) / 4;
+
+const TRANSFER_SIZE: usize = 0x1000;
+const BORDER_TILES: usize = 256;
+const BORDER_MAP_WIDTH: usize = 32;
+const BORDER_MAP_SIZE: usize = 32 * 32;
+const BORDER_X_FLIP: u16 = 0x4000;
+const BORDER_Y_FLIP: u16 = 0x8000;
+
+// Commands
+const PAL01: u8     = 0x00;
+const PAL23: u8     = 0x01;
+const PAL03: u8     = 0x02;
+const PAL12: u8     = 0x03;
+const ATTR_BLK: u8  = 0x04;
+const ATTR_LIN: u8  = 0x05;
+const ATTR_DIV: u8  = 0x06;
+const ATTR_CHR: u8  = 0x07;
+const PAL_SET: u8   = 0x0A;
+const PAL_TRN: u8   = 0x0B;
+const CHR_TRN: u8   = 0x13;
+const PCT_TRN: u8   = 0x14;
+const ATTR_TRN: u8  = 0x15;
+const ATTR_SET: u8  = 0x16;
+const MASK_EN: u8   = 0x17;
+
+// Default SGB palette (1-A).
+const DEFAULT_COLOURS: PaletteColours = [
+    Colour::new(0xF8, 0xE8, 0xC8),
+    Colour::new(0xD8, 0x90, 0x48),
+    Colour::new(0xA8, 0x28, 0x20),
+    Colour::new(0x30, 0x18, 0x50)
+];
+
+// Screen masking, set by MASK_EN.
+#[derive(Clone, Copy, PartialEq)]
+enum Mask {
+    None,
+    Freeze,     // Keep showing the current frame.
+    Black,
+    Colour0     // Fill with colour 0.
+}
+
+// Data to read from the screen on the next frame.
+#[derive(Clone, Copy)]
+enum Transfer {
+    SystemPalettes,
+    BorderTiles(usize), // Start tile
+    BorderMap,
+    AttrFiles
+}
+
+// The border around the screen. The renderer owns the SGB state, and sends a copy of this when it changes.
+#[derive(Clone)]
+pub struct SGBBorder {
+    tiles:      Vec<[u8; 64]>,  // 4-bit texels
+    map:        Vec<u16>,
+    palettes:   [[Colour; 16]; 4],
+    backdrop:   Colour,         // SGB colour 0.
+}
+
+impl SGBBorder {
+    pub fn new() -> Self {
+        SGBBorder {
+            tiles:      vec![[0; 64]; BORDER_TILES],
+            map:        vec![0; BORDER_MAP_SIZE],
+            palettes:   [[Colour::new(0, 0, 0); 16]; 4],
+            backdrop:   DEFAULT_COLOURS[0],
+        }
+    }
+
+    // Draw the border around the screen. The screen is 160x144, the output is 256x224.
+    // For indexed output, border pixels are 0x40 + (palette * 16) + colour, or 0 for the backdrop.
+    pub fn draw(&self, screen: &[u8], output: &mut [u8], format: PixelFormat) {
+        let bytes_per_pixel = format.bytes_per_pixel();
+        let backdrop = Pixel { colour: self.backdrop, index: 0 };
+        for (y, line) in output.chunks_mut(SGB_FRAME_WIDTH * bytes_per_pixel).take(SGB_FRAME_HEIGHT).enumerate() {
+            for (x, out) in line.chunks_mut(bytes_per_pixel).enumerate() {
+                let screen_x = x.wrapping_sub(SCREEN_X);
+                let screen_y = y.wrapping_sub(SCREEN_Y);
+                if (screen_x < SCREEN_WIDTH) && (screen_y < SCREEN_HEIGHT) {
+                    let index = ((screen_y * SCREEN_WIDTH) + screen_x) * bytes_per_pixel;
+                    out.copy_from_slice(&screen[index..(index + bytes_per_pixel)]);
+                    continue;
+                }
+
+                let entry = self.map[((y / 8) * BORDER_MAP_WIDTH) + (x / 8)];
+                let tile = &self.tiles[(entry & 0xFF) as usize];
+                let tile_x = if (entry & BORDER_X_FLIP) != 0 {7 - (x % 8)} else {x % 8};
+                let tile_y = if (entry & BORDER_Y_FLIP) != 0 {7 - (y % 8)} else {y % 8};
+                let texel = tile[(tile_y * 8) + tile_x];
+                // Border palettes are numbered 4-7.
+                let palette = ((entry >> 10) & 3) as usize;
+                let pixel = if texel == 0 {
+                    backdrop
+                } else {
+                    Pixel {
+                        colour: self.palettes[palette][texel as usize],
+                        index:  0x40 + (palette as u8 * 16) + texel
+                    }
+                };
+                write_pixel(out, pixel, format);
+            }
+        }
+    }
+}
+
+pub struct SuperGameBoy {
+    palettes:           [PaletteColours; 4],
+    system_palettes:    Vec<PaletteColours>,
+    attr_map:           Vec<u8>,
+    attr_files:         Vec<u8>,
+    mask:               Mask,
+
+    border:             SGBBorder,
+    border_changed:     bool,           // The border has changed since it was last taken.
+
+    // VRAM transfers
+    screen:             Vec<u8>,        // Shades of the last frame drawn.
+    transfer:           Option<Transfer>,
+    transfer_started:   bool,           // The frame to read from has started.
+}
+
+impl SuperGameBoy {
+    pub fn new() -> Self {
+        SuperGameBoy {
+            palettes:           [DEFAULT_COLOURS; 4],
+            system_palettes:    vec![[Colour::new(0, 0, 0); 4]; NUM_SYSTEM_PALETTES],
+            attr_map:           vec![0; SGB_BLOCKS_X * SGB_BLOCKS_Y],
+            attr_files:         vec![0; NUM_ATTR_FILES * ATTR_FILE_SIZE],
+            mask:               Mask::None,
+
+            border:             SGBBorder::new(),
+            border_changed:     false,
+
+            screen:             vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            transfer:           None,
+            transfer_started:   false,
+        }
+    }
+
+    // Get the colour of a pixel, given its GB shade.
+    #[inline]
+    pub fn get_colour(&self, x: usize, y: usize, shade: u8) -> Colour {
+        match self.mask {
+            Mask::Black => Colour::new(0, 0, 0),
+            Mask::Colour0 => self.palettes[0][0],
+            _ => {
+                let palette = self.attr_map[((y / 8) * SGB_BLOCKS_X) + (x / 8)];
+                self.palettes[palette as usize][shade as usize]
+            }
+        }
+    }
+
+    // If true, the screen shouldn't be updated.
+    pub fn is_frozen(&self) -> bool {
+        self.mask == Mask::Freeze
+    }
+
+    // Store the shades of a line that was drawn. Once a full frame is drawn, any pending transfer is done.
+    pub fn capture_line(&mut self, y: usize, pixels: &[Pixel]) {
+        if y >= SCREEN_HEIGHT {
+            return;
+        }
+        for (shade, pixel) in self.screen[(y * SCREEN_WIDTH)..((y + 1) * SCREEN_WIDTH)].iter_mut().zip(pixels.iter()) {
+            *shade = pixel.index;
+        }
+
+        if y == 0 {
+            self.transfer_started = self.transfer.is_some();
+        } else if (y == SCREEN_HEIGHT - 1) && self.transfer_started {
+            self.transfer_started = false;
+            if let Some(transfer) = self.transfer.take() {
+                self.do_transfer(transfer);
+            }
+        }
+    }
+
+    // Get a copy of the border, if it or the backdrop colour has changed since the last call.
+    pub fn take_border(&mut self) -> Option<SGBBorder> {
+        let backdrop = self.palettes[0][0];
+        if self.border_changed || (backdrop != self.border.backdrop) {
+            self.border.backdrop = backdrop;
+            self.border_changed = false;
+            Some(self.border.clone())
+        } else {
+            None
+        }
+    }
+
+    // Run a command. The data contains all packets of the command, including the header byte.
+    pub fn command(&mut self, data: &[u8]) {
+        match data[0] >> 3 {
+            PAL01       => self.set_palettes(0, 1, &data[1..]),
+            PAL23       => self.set_palettes(2, 3, &data[1..]),
+            PAL03       => self.set_palettes(0, 3, &data[1..]),
+            PAL12       => self.set_palettes(1, 2, &data[1..]),
+            ATTR_BLK    => self.attr_blk(&data[1..]),
+            ATTR_LIN    => self.attr_lin(&data[1..]),
+            ATTR_DIV    => self.attr_div(&data[1..]),
+            ATTR_CHR    => self.attr_chr(&data[1..]),
+            PAL_SET     => self.pal_set(&data[1..]),
+            PAL_TRN     => self.transfer = Some(Transfer::SystemPalettes),
+            CHR_TRN     => self.transfer = Some(Transfer::BorderTiles(if test_bit!(data[1], 0) {0x80} else {0})),
+            PCT_TRN     => self.transfer = Some(Transfer::BorderMap),
+            ATTR_TRN    => self.transfer = Some(Transfer::AttrFiles),
+            ATTR_SET    => {
+                self.apply_attr_file((data[1] & 0x3F) as usize);
+                if test_bit!(data[1], 6) {
+                    self.mask = Mask::None;
+                }
+            },
+            MASK_EN     => self.mask = match data[1] & 3 {
+                0 => Mask::None,
+                1 => Mask::Freeze,
+                2 => Mask::Black,
+                _ => Mask::Colour0,
+            },
+            _ => {},
+        }
+    }
+}
+
+// Commands
+impl SuperGameBoy {
+    // Colour 0 is shared by all palettes.
+    fn set_palettes(&mut self, a: usize, b: usize, data: &[u8]) {
+        let colour = |i: usize| Colour::from_555(make_16!(data[(i * 2) + 1], data[i * 2]));
+
+        let colour_0 = colour(0);
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour_0;
+        }
+        for i in 1..4 {
+            self.palettes[a][i] = colour(i);
+            self.palettes[b][i] = colour(i + 3);
+        }
+    }
+
+    // Colour rectangles, with separate palettes for the inside, border and outside.
+    fn attr_blk(&mut self, data: &[u8]) {
+        let num_sets = (data[0] & 0x1F) as usize;
+        for set in data[1..].chunks_exact(6).take(num_sets) {
+            let change_inside = test_bit!(set[0], 0);
+            let mut change_border = test_bit!(set[0], 1);
+            let change_outside = test_bit!(set[0], 2);
+            let pal_inside = set[1] & 3;
+            let mut pal_border = (set[1] >> 2) & 3;
+            let pal_outside = (set[1] >> 4) & 3;
+
+            // If only the inside or outside is changed, the border is changed with it.
+            if !change_border {
+                if change_inside && !change_outside {
+                    change_border = true;
+                    pal_border = pal_inside;
+                } else if change_outside && !change_inside {
+                    change_border = true;
+                    pal_border = pal_outside;
+                }
+            }
+
+            let (x1, y1, x2, y2) = ((set[2] & 0x1F) as usize, (set[3] & 0x1F) as usize, (set[4] & 0x1F) as usize, (set[5] & 0x1F) as usize);
+            for y in 0..SGB_BLOCKS_Y {
+                for x in 0..SGB_BLOCKS_X {
+                    let inside_x = (x > x1) && (x < x2);
+                    let inside_y = (y > y1) && (y < y2);
+                    let on_x = (x >= x1) && (x <= x2);
+                    let on_y = (y >= y1) && (y <= y2);
+
+                    let attr = &mut self.attr_map[(y * SGB_BLOCKS_X) + x];
+                    if inside_x && inside_y {
+                        if change_inside {
+                            *attr = pal_inside;
+                        }
+                    } else if on_x && on_y {
+                        if change_border {
+                            *attr = pal_border;
+                        }
+                    } else if change_outside {
+                        *attr = pal_outside;
+                    }
+                }
+            }
+        }
+    }
+
+    // Colour whole rows or columns.
+    fn attr_lin(&mut self, data: &[u8]) {
+        let num_sets = data[0] as usize;
+        for set in data[1..].iter().take(num_sets) {
+            let line = (set & 0x1F) as usize;
+            let palette = (set >> 5) & 3;
+            if test_bit!(*set, 7) {
+                if line < SGB_BLOCKS_Y {
+                    for attr in self.attr_map.iter_mut().skip(line * SGB_BLOCKS_X).take(SGB_BLOCKS_X) {
+                        *attr = palette;
+                    }
+                }
+            } else if line < SGB_BLOCKS_X {
+                for attr in self.attr_map.iter_mut().skip(line).step_by(SGB_BLOCKS_X) {
+                    *attr = palette;
+                }
+            }
+        }
+    }
+
+    // Split the screen into two halves, with a line between them.
+    fn attr_div(&mut self, data: &[u8]) {
+        let pal_after = data[0] & 3;
+        let pal_before = (data[0] >> 2) & 3;
+        let pal_line = (data[0] >> 4) & 3;
+        let horizontal = test_bit!(data[0], 6);
+        let div = (data[1] & 0x1F) as usize;
+
+        for y in 0..SGB_BLOCKS_Y {
+            for x in 0..SGB_BLOCKS_X {
+                let pos = if horizontal {y} else {x};
+                self.attr_map[(y * SGB_BLOCKS_X) + x] = if pos < div {
+                    pal_before
+                } else if pos == div {
+                    pal_line
+                } else {
+                    pal_after
+                };
+            }
+        }
+    }
+
+    // Set individual blocks, 4 per byte.
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = (data[0] as usize) % SGB_BLOCKS_X;
+        let mut y = (data[1] as usize) % SGB_BLOCKS_Y;
+        let num_blocks = (make_16!(data[3], data[2]) as usize).min(SGB_BLOCKS_X * SGB_BLOCKS_Y);
+        let vertical = data[4] == 1;
+
+        let blocks = data[5..].iter().flat_map(|b| (0..4).rev().map(move |i| (b >> (i * 2)) & 3));
+        for palette in blocks.take(num_blocks) {
+            self.attr_map[(y * SGB_BLOCKS_X) + x] = palette;
+            if vertical {
+                y += 1;
+                if y >= SGB_BLOCKS_Y {
+                    y = 0;
+                    x = (x + 1) % SGB_BLOCKS_X;
+                }
+            } else {
+                x += 1;
+                if x >= SGB_BLOCKS_X {
+                    x = 0;
+                    y = (y + 1) % SGB_BLOCKS_Y;
+                }
+            }
+        }
+    }
+
+    // Copy system palettes into the SGB palettes.
+    fn pal_set(&mut self, data: &[u8]) {
+        for (i, palette) in self.palettes.iter_mut().enumerate() {
+            let index = (make_16!(data[(i * 2) + 1], data[i * 2]) as usize) % NUM_SYSTEM_PALETTES;
+            *palette = self.system_palettes[index];
+        }
+
+        let colour_0 = self.palettes[0][0];
+        for palette in self.palettes.iter_mut() {
+            palette[0] = colour_0;
+        }
+
+        if test_bit!(data[8], 7) {
+            self.apply_attr_file((data[8] & 0x3F) as usize);
+        }
+        if test_bit!(data[8], 6) {
+            self.mask = Mask::None;
+        }
+    }
+
+    // Set the attribute map from a file sent with ATTR_TRN. Each byte has 4 blocks.
+    fn apply_attr_file(&mut self, file: usize) {
+        if file >= NUM_ATTR_FILES {
+            return;
+        }
+        let start = file * ATTR_FILE_SIZE;
+        let blocks = self.attr_files[start..(start + ATTR_FILE_SIZE)].iter()
+            .flat_map(|b| (0..4).rev().map(move |i| (b >> (i * 2)) & 3));
+        for (attr, palette) in self.attr_map.iter_mut().zip(blocks) {
+            *attr = palette;
+        }
+    }
+}
+
+// VRAM transfers
+impl SuperGameBoy {
+    // Read the screen as tiles, 20 per row. Each pixel's shade is stored as 2 bits, the same way as GB tile data.
+    fn read_screen(&self) -> Vec<u8> {
+        let mut data = vec![0; TRANSFER_SIZE];
+        for (tile, tile_data) in data.chunks_exact_mut(16).enumerate() {
+            let tile_x = (tile % SGB_BLOCKS_X) * 8;
+            let tile_y = (tile / SGB_BLOCKS_X) * 8;
+            for (row, bytes) in tile_data.chunks_exact_mut(2).enumerate() {
+                let start = ((tile_y + row) * SCREEN_WIDTH) + tile_x;
+                for (x, shade) in self.screen[start..(start + 8)].iter().enumerate() {
+                    bytes[0] |= (shade & 1) << (7 - x);
+                    bytes[1] |= ((shade >> 1) & 1) << (7 - x);
+                }
+            }
+        }
+        data
+    }
+
+    fn do_transfer(&mut self, transfer: Transfer) {
+        let data = self.read_screen();
+        let colour = |i: usize| Colour::from_555(make_16!(data[(i * 2) + 1], data[i * 2]));
+        if let Transfer::BorderTiles(_) | Transfer::BorderMap = transfer {
+            self.border_changed = true;
+        }
+        match transfer {
+            Transfer::SystemPalettes => for (i, palette) in self.system_palettes.iter_mut().enumerate() {
+                for (j, c) in palette.iter_mut().enumerate() {
+                    *c = colour((i * 4) + j);
+                }
+            },
+            // Tiles are SNES 4-bit format: bitplanes 0 and 1 for each row, followed by bitplanes 2 and 3.
+            Transfer::BorderTiles(start) => for (tile, tile_data) in self.border.tiles.iter_mut().skip(start).zip(data.chunks_exact(32)) {
+                for (i, texel) in tile.iter_mut().enumerate() {
+                    let (x, y) = (7 - (i % 8), i / 8);
+                    *texel = ((tile_data[y * 2] >> x) & 1) |
+                        (((tile_data[(y * 2) + 1] >> x) & 1) << 1) |
+                        (((tile_data[(y * 2) + 16] >> x) & 1) << 2) |
+                        (((tile_data[(y * 2) + 17] >> x) & 1) << 3);
+                }
+            },
+            // The map is followed by palettes 4-7.
+            Transfer::BorderMap => {
+                for (i, entry) in self.border.map.iter_mut().enumerate() {
+                    *entry = make_16!(data[(i * 2) + 1], data[i * 2]);
+                }
+                let palette_start = BORDER_MAP_SIZE;
+                for (i, palette) in self.border.palettes.iter_mut().enumerate() {
+                    for (j, c) in palette.iter_mut().enumerate() {
+                        *c = colour(palette_start + (i * 16) + j);
+                    }
+                }
+            },
+            Transfer::AttrFiles => {
+                let len = self.attr_files.len();
+                self.attr_files.copy_from_slice(&data[0..len]);
+            }
+        }
+    }
+}
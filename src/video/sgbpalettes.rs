@@ -17,17 +17,117 @@ use super::{
 pub enum UserPalette {
     Default,
     Greyscale,
-    Classic
+    Classic,
+    Compatibility(Option<CompatibilityPreset>), // CGB palettes for GB games: from the title, or a preset.
+    SuperGameBoy,                               // Colours set by the game through SGB commands.
+    Custom(SGBPalette)                          // User-defined colours, e.g. from SGBPalette::from_file.
+}
+
+// Preset palettes the CGB offers for GB games.
+// These are chosen by holding a direction and optionally a button during the boot logo.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompatibilityPreset {
+    Up,     // Brown
+    UpA,    // Red
+    UpB,    // Dark brown
+    Left,   // Blue
+    LeftA,  // Dark blue
+    LeftB,  // Greyscale
+    Down,   // Pastel
+    DownA,  // Orange
+    DownB,  // Yellow
+    Right,  // Green
+    RightA, // Dark green
+    RightB  // Inverted
+}
+
+impl CompatibilityPreset {
+    pub fn palette(self) -> SGBPalette {
+        use CompatibilityPreset::*;
+        compatibility_palette(match self {
+            Up      => 5,
+            UpA     => 43,
+            UpB     => 28,
+            Left    => 48,
+            LeftA   => 40,
+            LeftB   => 7,
+            Down    => 8,
+            DownA   => 3,
+            DownB   => 49,
+            Right   => 1,
+            RightA  => 0,
+            RightB  => 6,
+        })
+    }
 }
 
 // Palette for use with super game boy.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct SGBPalette {
     pub bg: PaletteColours,
     pub obj0: PaletteColours,
     pub obj1: PaletteColours
 }
 
+impl SGBPalette {
+    // Load a palette file. See SGBPalette::parse for the formats.
+    pub fn from_file(file_name: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(file_name).map_err(|e| format!("Couldn't read {}: {}", file_name, e))?;
+        Self::parse(&text).map_err(|e| format!("Couldn't load {}: {}", file_name, e))
+    }
+
+    // Parse a palette from text. Colours are from lightest to darkest.
+    // 4 colours are used for the background and both object palettes.
+    // 12 colours are split into background, object 0 and object 1 palettes.
+    // Formats:
+    //  - JASC-PAL: a "JASC-PAL" header, version and count lines, then decimal "R G B" lines.
+    //  - Hex lists: one hex colour per line, as "RRGGBB", "#RRGGBB" or "0xRRGGBB".
+    //    Blank lines and lines starting with ';' or "//" are ignored.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty());
+        let colours = if lines.clone().next() == Some("JASC-PAL") {
+            lines.skip(3).map(parse_decimal_colour).collect::<Result<Vec<_>, _>>()?
+        } else {
+            lines.filter(|l| !l.starts_with(';') && !l.starts_with("//"))
+                .map(parse_hex_colour)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        let palette = |i: usize| [colours[i], colours[i + 1], colours[i + 2], colours[i + 3]];
+        match colours.len() {
+            4 => Ok(SGBPalette {
+                bg: palette(0),
+                obj0: palette(0),
+                obj1: palette(0)
+            }),
+            12 => Ok(SGBPalette {
+                bg: palette(0),
+                obj0: palette(4),
+                obj1: palette(8)
+            }),
+            n => Err(format!("expected 4 or 12 colours, found {}", n))
+        }
+    }
+}
+
+fn parse_hex_colour(line: &str) -> Result<Colour, String> {
+    let hex = line.trim_start_matches('#').trim_start_matches("0x").trim_start_matches("0X");
+    if hex.len() != 6 {
+        return Err(format!("invalid colour \"{}\"", line));
+    }
+    let rgb = u32::from_str_radix(hex, 16).map_err(|_| format!("invalid colour \"{}\"", line))?;
+    Ok(Colour::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+fn parse_decimal_colour(line: &str) -> Result<Colour, String> {
+    let channels = line.split_whitespace().map(|c| c.parse::<u8>()).collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("invalid colour \"{}\"", line))?;
+    if channels.len() != 3 {
+        return Err(format!("invalid colour \"{}\"", line));
+    }
+    Ok(Colour::new(channels[0], channels[1], channels[2]))
+}
+
 // GB greyscale palette
 const BW_COLOURS: PaletteColours = [
     make_colour!(0xFF, 0xFF, 0xFF),
@@ -58,13 +158,32 @@ pub const CLASSIC_PALETTE: SGBPalette = SGBPalette {
 
 // CGB/SGB palette lookup table.
 pub fn lookup_sgb_palette(hash_in: u8, char_4_in: u8) -> SGBPalette {
-    for (hash, char_4, palette) in SGB_PALETTE_TABLE {
-        if (*hash == hash_in) && ((*char_4 == 0) || (*char_4 == char_4_in)) {
-            return *palette;
+    find_palette(hash_in, char_4_in).unwrap_or(BW_PALETTE)
+}
+
+// CGB palette lookup for GB games, using the tables from the CGB boot ROM.
+// The title checksum (and the 4th letter of the title, for checksums shared by multiple games) picks a combination of palettes.
+// Games not in the table, or without a Nintendo licensee code, get the default combination (the same as Right + A).
+pub fn lookup_compatibility_palette(cart_hash: Option<(u8, u8)>) -> SGBPalette {
+    let combination = cart_hash.and_then(|(hash, char_4)| {
+        let index = TITLE_CHECKSUMS.iter().position(|c| *c == hash)?;
+        if index < AMBIGUOUS_CHECKSUMS_START {
+            Some(index)
+        } else {
+            // The ambiguous checksums repeat in rows, each with its own set of letters.
+            (index..TITLE_CHECKSUMS.len())
+                .step_by(NUM_AMBIGUOUS_CHECKSUMS)
+                .find(|i| TITLE_FOURTH_LETTERS[i - AMBIGUOUS_CHECKSUMS_START] == char_4)
         }
-    }
+    }).map_or(0, |index| CHECKSUM_COMBINATIONS[index]);
+    compatibility_palette(combination)
+}
 
-    BW_PALETTE
+fn find_palette(hash_in: u8, char_4_in: u8) -> Option<SGBPalette> {
+    // A 4th letter of 0 in the table matches any title.
+    SGB_PALETTE_TABLE.iter()
+        .find(|(hash, char_4, _)| (*hash == hash_in) && ((*char_4 == 0) || (*char_4 == char_4_in)))
+        .map(|(_, _, palette)| *palette)
 }
 
 const SGB_PALETTE_TABLE: &[(u8, u8, SGBPalette)] = &[
@@ -272,4 +391,154 @@ const MARIO_LAND: SGBPalette = SGBPalette {
         make_colour!(0xFF, 0x84, 0x84),
         make_colour!(0x94, 0x3A, 0x3A)
     ]
-};
\ No newline at end of file
+};
+// CGB compatibility palettes, from the boot ROM.
+// The palettes are stored one after another, and combinations can start part way through a palette.
+fn compatibility_palette(combination: usize) -> SGBPalette {
+    let colours = |start: usize| {
+        let mut palette = [Colour::new(0, 0, 0); 4];
+        for (i, colour) in palette.iter_mut().enumerate() {
+            let rgb = COMPATIBILITY_COLOURS[(start + i) / 4][(start + i) % 4];
+            *colour = make_colour!(expand_5bit(rgb), expand_5bit(rgb >> 5), expand_5bit(rgb >> 10));
+        }
+        palette
+    };
+    let (obj0, obj1, bg) = PALETTE_COMBINATIONS[combination];
+    SGBPalette {
+        bg: colours(bg),
+        obj0: colours(obj0),
+        obj1: colours(obj1)
+    }
+}
+
+// Index of the first colour of a palette.
+const fn pal(palette: usize) -> usize {
+    palette * 4
+}
+
+#[inline]
+fn expand_5bit(val: u16) -> u8 {
+    let val = (val & 0x1F) as u32;
+    (((val * 255) + 15) / 31) as u8
+}
+
+// Title checksums. The first 65 are unique, then the next 14 are shared by multiple games.
+// The shared checksums repeat so each can be matched with more 4th letters.
+const AMBIGUOUS_CHECKSUMS_START: usize = 65;
+const NUM_AMBIGUOUS_CHECKSUMS: usize = 14;
+
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3,
+];
+
+// 4th letter of the title for each of the shared checksums.
+const TITLE_FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Palette combination for each title checksum.
+const CHECKSUM_COMBINATIONS: [usize; 94] = [
+    0,  4,  5,  35, 34, 3,  31, 15, 10, 5,  19, 36, 7,  37, 30, 44,
+    21, 32, 31, 20, 5,  33, 13, 14, 5,  29, 5,  18, 9,  3,  2,  26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5,  42, 6,  5,  33, 25, 42, 42, 40, 2,  16, 25, 42, 42, 5,  0,
+    39,
+    36, 22, 25, 6,  32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6,  27, 0,  47, 41, 41, 0,  0,  19, 34, 23, 18,
+    29,
+];
+
+// OBJ0, OBJ1 and BG palettes for each combination, as the index of the first colour.
+// A few start on the last colour of the previous palette.
+const PALETTE_COMBINATIONS: [(usize, usize, usize); 51] = [
+    (pal(4),      pal(4),      pal(29)),      // 0: Right + A
+    (pal(18),     pal(18),     pal(18)),      // 1: Right
+    (pal(20),     pal(20),     pal(20)),
+    (pal(24),     pal(24),     pal(24)),      // 3: Down + A
+    (pal(9),      pal(9),      pal(9)),
+    (pal(0),      pal(0),      pal(0)),       // 5: Up
+    (pal(27),     pal(27),     pal(27)),      // 6: Right + B
+    (pal(5),      pal(5),      pal(5)),       // 7: Left + B
+    (pal(12),     pal(12),     pal(12)),      // 8: Down
+    (pal(26),     pal(26),     pal(26)),
+    (pal(16),     pal(8),      pal(8)),       // 10
+    (pal(4),      pal(28),     pal(28)),
+    (pal(4),      pal(2),      pal(2)),
+    (pal(3),      pal(4),      pal(4)),
+    (pal(4),      pal(29),     pal(29)),
+    (pal(28),     pal(4),      pal(28)),
+    (pal(2),      pal(17),     pal(2)),
+    (pal(16),     pal(16),     pal(8)),
+    (pal(4),      pal(4),      pal(7)),
+    (pal(4),      pal(4),      pal(18)),
+    (pal(4),      pal(4),      pal(20)),      // 20
+    (pal(19),     pal(19),     pal(9)),
+    (pal(4) - 1,  pal(4) - 1,  pal(11)),
+    (pal(17),     pal(17),     pal(2)),
+    (pal(4),      pal(4),      pal(2)),
+    (pal(4),      pal(4),      pal(3)),
+    (pal(28),     pal(28),     pal(0)),
+    (pal(3),      pal(3),      pal(0)),
+    (pal(0),      pal(0),      pal(1)),       // 28: Up + B
+    (pal(18),     pal(22),     pal(18)),
+    (pal(20),     pal(22),     pal(20)),      // 30
+    (pal(24),     pal(22),     pal(24)),
+    (pal(16),     pal(22),     pal(8)),
+    (pal(17),     pal(4),      pal(13)),
+    (pal(28) - 1, pal(0),      pal(14)),
+    (pal(28) - 1, pal(4),      pal(15)),
+    (pal(19),     pal(22),     pal(9)),
+    (pal(16),     pal(28),     pal(10)),
+    (pal(4),      pal(23),     pal(28)),
+    (pal(17),     pal(22),     pal(2)),
+    (pal(4),      pal(0),      pal(2)),       // 40: Left + A
+    (pal(4),      pal(28),     pal(3)),
+    (pal(28),     pal(3),      pal(0)),
+    (pal(3),      pal(28),     pal(4)),       // 43: Up + A
+    (pal(21),     pal(28),     pal(4)),
+    (pal(3),      pal(28),     pal(0)),
+    (pal(25),     pal(3),      pal(28)),
+    (pal(0),      pal(28),     pal(8)),
+    (pal(4),      pal(3),      pal(28)),      // 48: Left
+    (pal(28),     pal(3),      pal(6)),       // 49: Down + B
+    (pal(4),      pal(28),     pal(29)),      // 50
+];
+
+// Colours in CGB format (xBBBBBGGGGGRRRRR), lightest to darkest.
+const COMPATIBILITY_COLOURS: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
@@ -1,4 +1,6 @@
-#[derive(Clone, Copy, Debug)]
+use bitflags::bitflags;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Colour {
     pub r: u8,
     pub g: u8,
@@ -14,6 +16,18 @@ impl Colour {
         }
     }
 
+    // Convert from 15-bit BGR colour, as used by CGB and SGB palettes.
+    pub fn from_555(rgb: u16) -> Colour {
+        const MAX_COLOUR: u16 = 0x1F;
+        let r_i = (rgb & MAX_COLOUR) << 3;
+        let g_i = ((rgb >> 5) & MAX_COLOUR) << 3;
+        let b_i = ((rgb >> 10) & MAX_COLOUR) << 3;
+        let r = r_i + (r_i >> 5);
+        let g = g_i + (g_i >> 5);
+        let b = b_i + (b_i >> 5);
+        Colour::new(r as u8, g as u8, b as u8)
+    }
+
     pub fn zero() -> Colour {
         Colour {
             r: 255,
@@ -23,4 +37,89 @@ impl Colour {
     }
 }
 
-pub type PaletteColours = [Colour; 4];
\ No newline at end of file
+pub type PaletteColours = [Colour; 4];
+
+// Format of pixels in the output frame.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PixelFormat {
+    RGBA8888,   // Bytes in the order R, G, B, A.
+    BGRA8888,   // Bytes in the order B, G, R, A.
+    XRGB8888,   // Native-endian 32-bit words: 0xFFRRGGBB.
+    RGB565,     // Native-endian 16-bit words.
+    Indexed     // One byte: the GB shade (0-3), or the CGB palette index (BG: 0-31, OBJ: 32-63).
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::RGB565 => 2,
+            PixelFormat::Indexed => 1,
+            _ => 4
+        }
+    }
+}
+
+// A finished pixel, with the palette index it came from.
+#[derive(Clone, Copy)]
+pub struct Pixel {
+    pub colour: Colour,
+    pub index:  u8
+}
+
+// Colour correction for CGB palettes, to emulate the look of the screen.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColourCorrection {
+    None,           // Linear mapping of 15-bit colours.
+    ColorLCD,       // Game Boy Color LCD: colours bleed together and lose saturation.
+    AdvanceLCD,     // Game Boy Advance LCD: darker, with a high gamma.
+    ReduceContrast  // Linear, but without full black or white.
+}
+
+impl ColourCorrection {
+    // Convert from 15-bit BGR colour.
+    pub fn convert(self, rgb: u16) -> Colour {
+        const MAX_COLOUR: u16 = 0x1F;
+        let r = (rgb & MAX_COLOUR) as u32;
+        let g = ((rgb >> 5) & MAX_COLOUR) as u32;
+        let b = ((rgb >> 10) & MAX_COLOUR) as u32;
+
+        match self {
+            ColourCorrection::None => Colour::from_555(rgb),
+            ColourCorrection::ColorLCD => {
+                let r_o = ((r * 26) + (g * 4) + (b * 2)).min(960) >> 2;
+                let g_o = ((g * 24) + (b * 8)).min(960) >> 2;
+                let b_o = ((r * 6) + (g * 4) + (b * 22)).min(960) >> 2;
+                Colour::new(r_o as u8, g_o as u8, b_o as u8)
+            },
+            ColourCorrection::AdvanceLCD => {
+                const LCD_GAMMA: f64 = 4.0;
+                const OUT_GAMMA: f64 = 2.2;
+                let lr = (r as f64 / 31.0).powf(LCD_GAMMA);
+                let lg = (g as f64 / 31.0).powf(LCD_GAMMA);
+                let lb = (b as f64 / 31.0).powf(LCD_GAMMA);
+                let out = |c: f64| ((c / 255.0).powf(1.0 / OUT_GAMMA) * (255.0 * 255.0 / 280.0)).min(255.0) as u8;
+                Colour::new(
+                    out((255.0 * lr) + (50.0 * lg)),
+                    out((10.0 * lr) + (230.0 * lg) + (30.0 * lb)),
+                    out((50.0 * lr) + (10.0 * lg) + (220.0 * lb))
+                )
+            },
+            ColourCorrection::ReduceContrast => {
+                const MIN_OUT: u32 = 0x20;
+                const MAX_OUT: u32 = 0xE0;
+                let out = |c: u32| (MIN_OUT + ((c * (MAX_OUT - MIN_OUT)) / MAX_COLOUR as u32)) as u8;
+                Colour::new(out(r), out(g), out(b))
+            }
+        }
+    }
+}
+
+bitflags! {
+    // Layers drawn to the output. Hidden layers show as colour 0.
+    // This only affects drawing: the game can't tell.
+    pub struct Layers: u8 {
+        const BACKGROUND    = bit!(0);
+        const WINDOW        = bit!(1);
+        const SPRITES       = bit!(2);
+    }
+}
@@ -1,3 +1,5 @@
+pub const SCREEN_WIDTH: usize = 160;        // Width of the screen in pixels.
 pub const TILE_DATA_WIDTH: usize = 16;      // Width of the tile data in tiles.
 pub const TILE_DATA_HEIGHT_GB: usize = 24;  // Height of the tile data in tiles for GB.
-pub const TILE_DATA_HEIGHT_CGB: usize = 48; // Height of the tile data in tiles for GB Color.
\ No newline at end of file
+pub const TILE_DATA_HEIGHT_CGB: usize = 48; // Height of the tile data in tiles for GB Color.
+pub const NUM_OBJECTS: usize = 40;          // Number of objects in OAM.
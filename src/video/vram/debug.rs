@@ -0,0 +1,155 @@
+// Debug viewers for VRAM: tiles, tile maps, objects and palettes.
+// Everything is drawn into RGBA8888 buffers, regardless of the output pixel format.
+use super::{
+    VRAM,
+    consts::*,
+    mapcache::{
+        MapCache,
+        TileAttributes
+    },
+    super::types::Colour,
+    super::regs::VideoRegs,
+};
+use crate::debug::*;
+
+const SCREEN_HEIGHT: usize = 144;
+const TILES_PER_BANK: usize = 384;
+const VIEWPORT_COLOUR: Colour = Colour::new(255, 0, 0);
+
+impl VRAM {
+    // Draw all tiles, with bank 1 to the right of bank 0.
+    // For GB, palette is 0 for BG and 1-2 for OBJ. For CGB, it is 0-7 for BG and 8-15 for OBJ.
+    pub fn draw_tile_sheet(&self, palette: usize, cgb_mode: bool, output: &mut [u8]) {
+        let banks = if cgb_mode {2} else {1};
+        output.fill(0);
+        for bank in 0..banks {
+            for i in 0..TILES_PER_BANK {
+                let tile = self.ref_tile((bank * TILES_PER_BANK) + i);
+                let base_x = ((bank * TILE_DATA_WIDTH) + (i % TILE_DATA_WIDTH)) * 8;
+                let base_y = (i / TILE_DATA_WIDTH) * 8;
+                for y in 0..8 {
+                    for x in 0..8 {
+                        let colour = self.palette_colour(palette, cgb_mode, tile.get_texel(x, y));
+                        let index = (((base_y + y) * TILE_SHEET_WIDTH) + base_x + x) * 4;
+                        write_rgba(&mut output[index..(index + 4)], colour);
+                    }
+                }
+            }
+        }
+    }
+
+    // Draw one of the two tile maps (0x9800 or 0x9C00), with the background viewport outlined.
+    pub fn draw_tile_map(&self, map_1: bool, regs: &VideoRegs, cgb_mode: bool, output: &mut [u8]) {
+        let (tile_map, tile_attrs) = if map_1 {(&self.tile_map_1, &self.tile_attrs_1)} else {(&self.tile_map_0, &self.tile_attrs_0)};
+        let mut map = MapCache::new(cgb_mode);
+        if cgb_mode {
+            map.construct_cgb(tile_map, tile_attrs, &self.tile_mem, regs);
+        } else {
+            map.construct_gb(tile_map, &self.tile_mem, regs);
+        }
+
+        for y in 0..TILE_MAP_SIZE {
+            for x in 0..TILE_MAP_SIZE {
+                let texel = map.get_texel(x, y);
+                let palette = if cgb_mode {(map.get_attrs(x, y) & TileAttributes::CGB_PAL).bits() as usize} else {0};
+                let index = ((y * TILE_MAP_SIZE) + x) * 4;
+                write_rgba(&mut output[index..(index + 4)], self.palette_colour(palette, cgb_mode, texel));
+            }
+        }
+
+        // The viewport wraps around the edges of the map.
+        if map_1 == regs.bg_tile_map_select() {
+            let (left, top) = (regs.scroll_x as usize, regs.scroll_y as usize);
+            let mut outline = |x: usize, y: usize| {
+                let index = (((y % TILE_MAP_SIZE) * TILE_MAP_SIZE) + (x % TILE_MAP_SIZE)) * 4;
+                write_rgba(&mut output[index..(index + 4)], VIEWPORT_COLOUR);
+            };
+            for x in left..(left + SCREEN_WIDTH) {
+                outline(x, top);
+                outline(x, top + SCREEN_HEIGHT - 1);
+            }
+            for y in top..(top + SCREEN_HEIGHT) {
+                outline(left, y);
+                outline(left + SCREEN_WIDTH - 1, y);
+            }
+        }
+    }
+
+    // List all objects in OAM, with previews as they would appear on screen.
+    pub fn get_object_info(&self, regs: &VideoRegs, cgb_mode: bool) -> Vec<ObjectInfo> {
+        let large = regs.is_large_sprites();
+        let height = if large {16} else {8};
+        self.object_mem.ref_objects().iter().map(|o| {
+            let palette = if cgb_mode {o.cgb_palette()} else if o.palette_0() {0} else {1};
+            let bank_offset = if cgb_mode {o.bank_offset()} else {0};
+
+            let mut preview = vec![0; OBJECT_PREVIEW_WIDTH * height * 4];
+            for y in 0..height {
+                let row = if o.flip_y() {height - 1 - y} else {y};
+                let tile_num = o.tile_num.wrapping_add((row / 8) as u8);
+                let tile = self.ref_tile((tile_num as usize) + bank_offset);
+                for x in 0..OBJECT_PREVIEW_WIDTH {
+                    let tile_x = if o.flip_x() {7 - x} else {x};
+                    let texel = tile.get_texel(tile_x, row % 8);
+                    if texel != 0 {
+                        let colour_palette = if cgb_mode {8 + palette as usize} else {1 + palette as usize};
+                        let index = ((y * OBJECT_PREVIEW_WIDTH) + x) * 4;
+                        write_rgba(&mut preview[index..(index + 4)], self.palette_colour(colour_palette, cgb_mode, texel));
+                    }
+                }
+            }
+
+            ObjectInfo {
+                index:      o.index as usize,
+                x:          o.x,
+                y:          o.y,
+                tile_num:   o.tile_num,
+                flags:      o.flags.bits(),
+
+                above_bg:   o.is_above_bg(),
+                flip_x:     o.flip_x(),
+                flip_y:     o.flip_y(),
+                palette,
+                bank:       if bank_offset == 0 {0} else {1},
+
+                preview,
+            }
+        }).collect()
+    }
+
+    // Draw each palette as a row of 4 colours: 8 BG palettes then 8 OBJ palettes for CGB,
+    // or the BG, OBJ 0 and OBJ 1 palettes for GB.
+    pub fn draw_palettes(&self, cgb_mode: bool, output: &mut [u8]) {
+        let num_palettes = if cgb_mode {16} else {3};
+        output.fill(0);
+        for palette in 0..num_palettes {
+            for y in 0..8 {
+                for x in 0..PALETTE_VIEW_WIDTH {
+                    let colour = self.palette_colour(palette, cgb_mode, (x / 8) as u8);
+                    let index = ((((palette * 8) + y) * PALETTE_VIEW_WIDTH) + x) * 4;
+                    write_rgba(&mut output[index..(index + 4)], colour);
+                }
+            }
+        }
+    }
+
+    // Colour a texel with one of the palettes, numbered as in draw_tile_sheet.
+    fn palette_colour(&self, palette: usize, cgb_mode: bool, texel: u8) -> Colour {
+        if cgb_mode {
+            let which = palette % 16;
+            if which < 8 {
+                self.colour_palettes.get_bg_colour(which, texel)
+            } else {
+                self.colour_palettes.get_obj_colour(which - 8, texel)
+            }
+        } else {
+            let which = palette % 3;
+            self.palettes.get_shade_colour(which, self.palettes.get_shade(which, texel))
+        }
+    }
+}
+
+#[inline]
+fn write_rgba(output: &mut [u8], colour: Colour) {
+    output.copy_from_slice(&[colour.r, colour.g, colour.b, 255]);
+}
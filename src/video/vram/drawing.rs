@@ -1,70 +1,82 @@
 use super::{
     VRAM,
     sprite::Sprite,
-    super::types::Colour,
+    super::types::{
+        Colour,
+        Pixel,
+        PixelFormat,
+        Layers
+    },
     super::regs::VideoRegs,
     mapcache::TileAttributes,
+    consts::SCREEN_WIDTH,
 };
 
-const SCREEN_WIDTH: usize = 160;
-
 impl VRAM {
     pub fn draw_line_gb(&mut self, target: &mut [u8], regs: &VideoRegs) {
         let y = regs.read_lcdc_y();
         let target_start = (y as usize) * SCREEN_WIDTH;
+        let bytes_per_pixel = self.pixel_format.bytes_per_pixel();
 
         // Rebuild caches
         self.map_cache_0.construct_gb(&self.tile_map_0, &self.tile_mem, regs);
         self.map_cache_1.construct_gb(&self.tile_map_1, &self.tile_mem, regs);
 
         // Find objects
-        let objects = self.get_objects_for_line(y, regs);
+        let objects = self.get_objects_by_priority(y, regs);
         let mut sprite_pixels = [SpritePixel::None; SCREEN_WIDTH];
 
         self.render_sprites_to_line(&mut sprite_pixels, &objects, y, regs.is_large_sprites());
 
-        for (x, i) in target.chunks_mut(4).skip(target_start).take(SCREEN_WIDTH).enumerate() {
-            match sprite_pixels[x] {
-                SpritePixel::Hi(c) => write_pixel(i, c),
-                SpritePixel::Lo(c) => if let Some(px) = self.window_pixel(x as u8, y, regs) {
+        let mut shades = [Shade::default(); SCREEN_WIDTH];
+        for (x, shade) in shades.iter_mut().enumerate() {
+            *shade = match sprite_pixels[x] {
+                SpritePixel::Hi(c) => c,
+                SpritePixel::Lo(c) => if let Some(px) = self.window_pixel(x as u8, regs) {
                     match px {
-                        BGPixel::Zero(_) => write_pixel(i, c),
-                        BGPixel::NonZero(win) => write_pixel(i, win),
+                        BGPixel::Zero(_) => c,
+                        BGPixel::NonZero(win) => win,
                     }
                 } else {
                     match self.background_pixel(x as u8, y, regs) {
-                        BGPixel::Zero(_) => write_pixel(i, c),
-                        BGPixel::NonZero(bg) => write_pixel(i, bg),
+                        BGPixel::Zero(_) => c,
+                        BGPixel::NonZero(bg) => bg,
                     }
                 },
-                SpritePixel::None => if let Some(px) = self.window_pixel(x as u8, y, regs) {
+                SpritePixel::None => if let Some(px) = self.window_pixel(x as u8, regs) {
                     match px {
-                        BGPixel::Zero(win) => write_pixel(i, win),
-                        BGPixel::NonZero(win) => write_pixel(i, win),
+                        BGPixel::Zero(win) => win,
+                        BGPixel::NonZero(win) => win,
                     }
                 } else {
                     match self.background_pixel(x as u8, y, regs) {
-                        BGPixel::Zero(bg) => write_pixel(i, bg),
-                        BGPixel::NonZero(bg) => write_pixel(i, bg),
+                        BGPixel::Zero(bg) => bg,
+                        BGPixel::NonZero(bg) => bg,
                     }
                 }
+            };
+        }
+
+        let pixels = shades.iter().enumerate().map(|(x, shade)| self.get_gb_pixel(x, y, *shade)).collect::<Vec<_>>();
+
+        // SGB reads the screen back for VRAM transfers, even while it is frozen.
+        if let Some(sgb) = &mut self.sgb {
+            sgb.capture_line(y as usize, &pixels);
+            if sgb.is_frozen() {
+                return;
             }
         }
+
+        for (i, pixel) in target.chunks_mut(bytes_per_pixel).skip(target_start).zip(pixels.iter()) {
+            write_pixel(i, *pixel, self.pixel_format);
+        }
     }
 
-    fn render_sprites_to_line(&self, line: &mut [SpritePixel], objects: &[Sprite], y: u8, large: bool) {
-        for o in objects.iter().take(10) {
-            let sprite_y = y + 16 - o.y;
-            let (tile_num_offset, tile_y) = match (large, sprite_y < 8, o.flip_y()) {
-                (false, true, false)    => (0_u8, sprite_y),
-                (false, true, true)     => (0_u8, 7 - sprite_y),
-                (true, true, false)     => (0_u8, sprite_y),
-                (true, false, false)    => (1_u8, sprite_y - 8),
-                (true, true, true)      => (1_u8, 7 - sprite_y),
-                (true, false, true)     => (0_u8, 15 - sprite_y),
-                _ => unreachable!("Cannot have small sprites with sprite_y >= 8")
-            };
-            let tile = self.ref_tile(o.tile_num.wrapping_add(tile_num_offset) as usize);
+    // Objects are drawn lowest priority first.
+    fn render_sprites_to_line(&self, line: &mut [SpritePixel<Shade>], objects: &[Sprite], y: u8, large: bool) {
+        for o in objects.iter().rev() {
+            let (tile_num, tile_y) = o.tile_and_row(y, large);
+            let tile = self.ref_tile(tile_num as usize);
 
             let start_x = (o.x as isize) - 8;
             for x_offset in 0..8 {
@@ -73,7 +85,7 @@ impl VRAM {
                     let tile_x = if o.flip_x() {7 - x_offset} else {x_offset};
                     let texel = tile.get_texel(tile_x as usize, tile_y as usize);
                     if texel != 0 {
-                        let pixel = if o.palette_0() {self.get_obj_0_colour(texel)} else {self.get_obj_1_colour(texel)};
+                        let pixel = if o.palette_0() {self.get_obj_0_shade(texel)} else {self.get_obj_1_shade(texel)};
                         line[x as usize] = if o.is_above_bg() {
                             SpritePixel::Hi(pixel)
                         } else {
@@ -88,16 +100,17 @@ impl VRAM {
     }
 
     #[inline]
-    fn window_pixel(&self, x: u8, y: u8, regs: &VideoRegs) -> Option<BGPixel> {
-        if regs.get_window_enable() && regs.get_background_priority() && (x + 7 >= regs.window_x) && (y >= regs.window_y) {
-            let win_x = (x + 7 - regs.window_x) as usize;
-            let win_y = (y - regs.window_y) as usize;
+    fn window_pixel(&self, x: u8, regs: &VideoRegs) -> Option<BGPixel<Shade>> {
+        let window_x = regs.window_start_x();
+        if regs.is_window_on_line() && regs.get_background_priority() && (x + 7 >= window_x) {
+            let win_x = (x + 7 - window_x) as usize;
+            let win_y = regs.window_line() as usize;
             let win_cache = self.ref_window(regs);
-            let win_texel = win_cache.get_texel(win_x, win_y);
+            let win_texel = if self.is_layer_visible(Layers::WINDOW) {win_cache.get_texel(win_x, win_y)} else {0};
             Some(if win_texel == 0 {
-                BGPixel::Zero(self.get_bg_colour(win_texel))
+                BGPixel::Zero(self.get_bg_shade(win_texel))
             } else {
-                BGPixel::NonZero(self.get_bg_colour(win_texel))
+                BGPixel::NonZero(self.get_bg_shade(win_texel))
             })
         } else {
             None
@@ -105,19 +118,19 @@ impl VRAM {
     }
 
     #[inline]
-    fn background_pixel(&self, x: u8, y: u8, regs: &VideoRegs) -> BGPixel {
+    fn background_pixel(&self, x: u8, y: u8, regs: &VideoRegs) -> BGPixel<Shade> {
         if regs.get_background_priority() {
             let bg_x = regs.scroll_x.wrapping_add(x) as usize;
             let bg_y = regs.scroll_y.wrapping_add(y) as usize;
             let bg_cache = self.ref_background(regs);
-            let bg_texel = bg_cache.get_texel(bg_x, bg_y);
+            let bg_texel = if self.is_layer_visible(Layers::BACKGROUND) {bg_cache.get_texel(bg_x, bg_y)} else {0};
             if bg_texel == 0 {
-                BGPixel::Zero(self.get_bg_colour(bg_texel))
+                BGPixel::Zero(self.get_bg_shade(bg_texel))
             } else {
-                BGPixel::NonZero(self.get_bg_colour(bg_texel))
+                BGPixel::NonZero(self.get_bg_shade(bg_texel))
             }
         } else {
-            BGPixel::Zero(Colour::zero())
+            BGPixel::Zero(Shade::default())
         }
     }
 }
@@ -126,74 +139,66 @@ impl VRAM {
     pub fn draw_line_cgb(&mut self, target: &mut [u8], regs: &VideoRegs) {
         let y = regs.read_lcdc_y();
         let target_start = (y as usize) * SCREEN_WIDTH;
+        let bytes_per_pixel = self.pixel_format.bytes_per_pixel();
+        let format = self.pixel_format;
 
         // Rebuild caches
         self.map_cache_0.construct_cgb(&self.tile_map_0, &self.tile_attrs_0, &self.tile_mem, regs);
         self.map_cache_1.construct_cgb(&self.tile_map_1, &self.tile_attrs_1, &self.tile_mem, regs);
 
         // Find objects
-        let objects = self.get_objects_for_line(y, regs);
+        let objects = self.get_objects_by_priority(y, regs);
         let mut sprite_pixels = [SpritePixel::None; SCREEN_WIDTH];
 
         self.render_sprites_to_line_cgb(&mut sprite_pixels, &objects, y, regs.is_large_sprites());
 
-        for (x, i) in target.chunks_mut(4).skip(target_start).take(SCREEN_WIDTH).enumerate() {
+        for (x, i) in target.chunks_mut(bytes_per_pixel).skip(target_start).take(SCREEN_WIDTH).enumerate() {
             match sprite_pixels[x] {
-                SpritePixel::Hi(c) => if let Some(px) = self.window_pixel_cgb(x as u8, y, regs) {
+                SpritePixel::Hi(c) => if let Some(px) = self.window_pixel_cgb(x as u8, regs) {
                     match px {
-                        CGBPixel::Hi(win) => write_pixel(i, win),
-                        _ => write_pixel(i, c),
+                        CGBPixel::Hi(win) => write_pixel(i, win, format),
+                        _ => write_pixel(i, c, format),
                     }
                 } else {
                     match self.background_pixel_cgb(x as u8, y, regs) {
-                        CGBPixel::Hi(bg) => write_pixel(i, bg),
-                        _ => write_pixel(i, c),
+                        CGBPixel::Hi(bg) => write_pixel(i, bg, format),
+                        _ => write_pixel(i, c, format),
                     }
                 },
-                SpritePixel::Lo(c) => if let Some(px) = self.window_pixel_cgb(x as u8, y, regs) {
+                SpritePixel::Lo(c) => if let Some(px) = self.window_pixel_cgb(x as u8, regs) {
                     match px {
-                        CGBPixel::LoZero(_) => write_pixel(i, c),
-                        CGBPixel::LoNonZero(win) => write_pixel(i, win),
-                        CGBPixel::Hi(win) => write_pixel(i, win),
+                        CGBPixel::LoZero(_) => write_pixel(i, c, format),
+                        CGBPixel::LoNonZero(win) => write_pixel(i, win, format),
+                        CGBPixel::Hi(win) => write_pixel(i, win, format),
                     }
                 } else {
                     match self.background_pixel_cgb(x as u8, y, regs) {
-                        CGBPixel::LoZero(_) => write_pixel(i, c),
-                        CGBPixel::LoNonZero(bg) => write_pixel(i, bg),
-                        CGBPixel::Hi(bg) => write_pixel(i, bg),
+                        CGBPixel::LoZero(_) => write_pixel(i, c, format),
+                        CGBPixel::LoNonZero(bg) => write_pixel(i, bg, format),
+                        CGBPixel::Hi(bg) => write_pixel(i, bg, format),
                     }
                 },
-                SpritePixel::None => if let Some(px) = self.window_pixel_cgb(x as u8, y, regs) {
+                SpritePixel::None => if let Some(px) = self.window_pixel_cgb(x as u8, regs) {
                     match px {
-                        CGBPixel::LoZero(win) => write_pixel(i, win),
-                        CGBPixel::LoNonZero(win) => write_pixel(i, win),
-                        CGBPixel::Hi(win) => write_pixel(i, win),
+                        CGBPixel::LoZero(win) => write_pixel(i, win, format),
+                        CGBPixel::LoNonZero(win) => write_pixel(i, win, format),
+                        CGBPixel::Hi(win) => write_pixel(i, win, format),
                     }
                 } else {
                     match self.background_pixel_cgb(x as u8, y, regs) {
-                        CGBPixel::LoZero(bg) => write_pixel(i, bg),
-                        CGBPixel::LoNonZero(bg) => write_pixel(i, bg),
-                        CGBPixel::Hi(bg) => write_pixel(i, bg),
+                        CGBPixel::LoZero(bg) => write_pixel(i, bg, format),
+                        CGBPixel::LoNonZero(bg) => write_pixel(i, bg, format),
+                        CGBPixel::Hi(bg) => write_pixel(i, bg, format),
                     }
                 }
             }
         }
     }
 
-    fn render_sprites_to_line_cgb(&self, line: &mut [SpritePixel], objects: &[Sprite], y: u8, large: bool) {
-        for o in objects.iter().take(10).rev() {
-            let sprite_y = y + 16 - o.y;
-            let (tile_num_offset, tile_y) = match (large, sprite_y < 8, o.flip_y()) {
-                (false, true, false)    => (0_u8, sprite_y),
-                (false, true, true)     => (0_u8, 7 - sprite_y),
-                (true, true, false)     => (0_u8, sprite_y),
-                (true, false, false)    => (1_u8, sprite_y - 8),
-                (true, true, true)      => (1_u8, 7 - sprite_y),
-                (true, false, true)     => (0_u8, 15 - sprite_y),
-                _ => unreachable!("Cannot have small sprites with sprite_y >= 8")
-            };
-            let tile_num = (o.tile_num.wrapping_add(tile_num_offset) as usize) + o.bank_offset();
-            let tile = self.ref_tile(tile_num);
+    fn render_sprites_to_line_cgb(&self, line: &mut [SpritePixel<Pixel>], objects: &[Sprite], y: u8, large: bool) {
+        for o in objects.iter().rev() {
+            let (tile_num, tile_y) = o.tile_and_row(y, large);
+            let tile = self.ref_tile((tile_num as usize) + o.bank_offset());
 
             let start_x = (o.x as isize) - 8;
             for x_offset in 0..8 {
@@ -203,7 +208,7 @@ impl VRAM {
                     let texel = tile.get_texel(tile_x as usize, tile_y as usize);
                     if texel != 0 {
                         let palette = o.cgb_palette();
-                        let pixel = self.get_gbc_obj_colour(palette, texel);
+                        let pixel = self.get_gbc_obj_pixel(palette, texel);
                         line[x as usize] = if o.is_above_bg() {
                             SpritePixel::Hi(pixel)
                         } else {
@@ -218,29 +223,18 @@ impl VRAM {
     }
 
     #[inline]
-    fn window_pixel_cgb(&self, x: u8, y: u8, regs: &VideoRegs) -> Option<CGBPixel> {
-        if regs.get_window_enable() && (x + 7 >= regs.window_x) && (y >= regs.window_y) {
-            let win_x = (x + 7 - regs.window_x) as usize;
-            let win_y = (y - regs.window_y) as usize;
+    fn window_pixel_cgb(&self, x: u8, regs: &VideoRegs) -> Option<CGBPixel> {
+        let window_x = regs.window_start_x();
+        if regs.is_window_on_line() && (x + 7 >= window_x) {
+            let win_x = (x + 7 - window_x) as usize;
+            let win_y = regs.window_line() as usize;
             let win_cache = self.ref_window(regs);
-            let win_texel = win_cache.get_texel(win_x, win_y);
+            let win_texel = if self.is_layer_visible(Layers::WINDOW) {win_cache.get_texel(win_x, win_y)} else {0};
             let attrs = win_cache.get_attrs(win_x, win_y);
             let palette = (attrs & TileAttributes::CGB_PAL).bits();
-            let colour = self.get_gbc_bg_colour(palette, win_texel);
+            let pixel = self.get_gbc_bg_pixel(palette, win_texel);
 
-            Some(if regs.get_background_priority() {
-                if attrs.contains(TileAttributes::PRIORITY) {
-                    CGBPixel::Hi(colour)
-                } else if win_texel == 0 {
-                    CGBPixel::LoZero(colour)
-                } else {
-                    CGBPixel::LoNonZero(colour)
-                }
-            } else if win_texel == 0 {
-                CGBPixel::LoZero(colour)
-            } else {
-                CGBPixel::LoNonZero(colour)
-            })
+            Some(CGBPixel::new(pixel, win_texel, attrs, regs))
         } else {
             None
         }
@@ -251,48 +245,132 @@ impl VRAM {
         let bg_x = regs.scroll_x.wrapping_add(x) as usize;
         let bg_y = regs.scroll_y.wrapping_add(y) as usize;
         let bg_cache = self.ref_background(regs);
-        let bg_texel = bg_cache.get_texel(bg_x, bg_y);
+        let bg_texel = if self.is_layer_visible(Layers::BACKGROUND) {bg_cache.get_texel(bg_x, bg_y)} else {0};
         let attrs = bg_cache.get_attrs(bg_x, bg_y);
         let palette = (attrs & TileAttributes::CGB_PAL).bits();
-        let colour = self.get_gbc_bg_colour(palette, bg_texel);
+        let pixel = self.get_gbc_bg_pixel(palette, bg_texel);
 
-        if regs.get_background_priority() {
-            if attrs.contains(TileAttributes::PRIORITY) {
-                CGBPixel::Hi(colour)
-            } else if bg_texel == 0 {
-                CGBPixel::LoZero(colour)
-            } else {
-                CGBPixel::LoNonZero(colour)
-            }
-        } else if bg_texel == 0 {
-            CGBPixel::LoZero(colour)
-        } else {
-            CGBPixel::LoNonZero(colour)
-        }
+        CGBPixel::new(pixel, bg_texel, attrs, regs)
     }
 }
 
+// A GB pixel before it is coloured.
+#[derive(Clone, Copy, Default)]
+pub struct Shade {
+    pub palette:    u8, // 0: BG, 1: OBJ 0, 2: OBJ 1
+    pub shade:      u8, // 0-3, from the palette register
+}
+
 #[derive(Clone, Copy)]
-enum SpritePixel {
-    Hi(Colour), // High priority
-    Lo(Colour), // Low priority
+enum SpritePixel<P> {
+    Hi(P), // High priority
+    Lo(P), // Low priority
     None
 }
 
-enum BGPixel {
-    NonZero(Colour),    // Colour 1-3
-    Zero(Colour),       // Zero colour (draw LO sprites above this)
+enum BGPixel<P> {
+    NonZero(P),     // Colour 1-3
+    Zero(P),        // Zero colour (draw LO sprites above this)
 }
 
 enum CGBPixel {
-    Hi(Colour),         // High priority (draw above everything)
-    LoNonZero(Colour),  // Low prio, Colour 1-3 (draw HI sprites above this)
-    LoZero(Colour),     // Low prio, zero colour (draw HI & LO sprites above this)
+    Hi(Pixel),          // High priority (draw above everything)
+    LoNonZero(Pixel),   // Low prio, Colour 1-3 (draw HI sprites above this)
+    LoZero(Pixel),      // Low prio, zero colour (draw HI & LO sprites above this)
 }
 
+impl CGBPixel {
+    // Resolve background priority.
+    // If LCDC bit 0 is clear, the background and window lose priority to all sprites.
+    // Otherwise, the tile attribute priority bit puts colours 1-3 above all sprites.
+    // Colour 0 is always below sprites.
+    #[inline]
+    fn new(pixel: Pixel, texel: u8, attrs: TileAttributes, regs: &VideoRegs) -> Self {
+        if !regs.get_background_priority() || (texel == 0) {
+            CGBPixel::LoZero(pixel)
+        } else if attrs.contains(TileAttributes::PRIORITY) {
+            CGBPixel::Hi(pixel)
+        } else {
+            CGBPixel::LoNonZero(pixel)
+        }
+    }
+}
+
+impl VRAM {
+    // Draw a line finished by the pixel FIFO.
+    // The FIFO runs without SGB data, so the SGB colours are applied here using the shade.
+    pub fn draw_line_pixels(&mut self, target: &mut [u8], y: u8, pixels: &mut [Pixel]) {
+        if let Some(sgb) = &mut self.sgb {
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                pixel.colour = sgb.get_colour(x, y as usize, pixel.index);
+            }
+            // SGB reads the screen back for VRAM transfers, even while it is frozen.
+            sgb.capture_line(y as usize, pixels);
+            if sgb.is_frozen() {
+                return;
+            }
+        }
+
+        write_line(target, y, pixels, self.pixel_format);
+    }
+}
+
+// Write a line of pixels produced elsewhere (e.g. by the pixel FIFO).
+fn write_line(target: &mut [u8], y: u8, pixels: &[Pixel], format: PixelFormat) {
+    let bytes_per_pixel = format.bytes_per_pixel();
+    let target_start = (y as usize) * SCREEN_WIDTH;
+    for (i, pixel) in target.chunks_mut(bytes_per_pixel).skip(target_start).zip(pixels.iter()) {
+        write_pixel(i, *pixel, format);
+    }
+}
+
+// Read a pixel in the output format. Indexed pixels are read as a grey level of the index.
 #[inline]
-fn write_pixel(output: &mut [u8], colour: Colour) {
-    output[0] = colour.r;
-    output[1] = colour.g;
-    output[2] = colour.b;
-}
\ No newline at end of file
+pub fn read_pixel(input: &[u8], format: PixelFormat) -> Pixel {
+    let (r, g, b, index) = match format {
+        PixelFormat::RGBA8888 => (input[0], input[1], input[2], 0),
+        PixelFormat::BGRA8888 => (input[2], input[1], input[0], 0),
+        PixelFormat::XRGB8888 => {
+            let word = u32::from_ne_bytes([input[0], input[1], input[2], input[3]]);
+            ((word >> 16) as u8, (word >> 8) as u8, word as u8, 0)
+        },
+        PixelFormat::RGB565 => {
+            let word = u16::from_ne_bytes([input[0], input[1]]);
+            let r = ((word >> 11) & 0x1F) as u8;
+            let g = ((word >> 5) & 0x3F) as u8;
+            let b = (word & 0x1F) as u8;
+            ((r << 3) | (r >> 2), (g << 2) | (g >> 4), (b << 3) | (b >> 2), 0)
+        },
+        PixelFormat::Indexed => (input[0], input[0], input[0], input[0]),
+    };
+    Pixel { colour: Colour::new(r, g, b), index }
+}
+
+// Write a pixel in the output format. The output is the size of one pixel.
+#[inline]
+pub fn write_pixel(output: &mut [u8], pixel: Pixel, format: PixelFormat) {
+    let colour = pixel.colour;
+    match format {
+        PixelFormat::RGBA8888 => {
+            output[0] = colour.r;
+            output[1] = colour.g;
+            output[2] = colour.b;
+            output[3] = 255;
+        },
+        PixelFormat::BGRA8888 => {
+            output[0] = colour.b;
+            output[1] = colour.g;
+            output[2] = colour.r;
+            output[3] = 255;
+        },
+        PixelFormat::XRGB8888 => {
+            let word = 0xFF00_0000 | ((colour.r as u32) << 16) | ((colour.g as u32) << 8) | (colour.b as u32);
+            output.copy_from_slice(&word.to_ne_bytes());
+        },
+        PixelFormat::RGB565 => {
+            let word = (((colour.r as u16) >> 3) << 11) | (((colour.g as u16) >> 2) << 5) | ((colour.b as u16) >> 3);
+            output.copy_from_slice(&word.to_ne_bytes());
+        },
+        PixelFormat::Indexed => output[0] = pixel.index,
+    }
+}
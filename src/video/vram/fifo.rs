@@ -0,0 +1,420 @@
+// Dot-accurate renderer, using background and sprite pixel FIFOs.
+// This runs during mode 3, one dot at a time. Registers are read as they are used,
+// so writes during mode 3 take effect from the next tile fetch or the next pixel out.
+// The fetcher takes 6 dots to get the tile number and data, then pushes 8 pixels when the background FIFO is empty.
+// A pixel is shifted out each dot when the background FIFO has pixels.
+// Sprites pause the fetcher and pixel output while they are fetched, and are mixed into the sprite FIFO.
+use std::collections::VecDeque;
+
+use super::{
+    VRAM,
+    Shade,
+    sprite::Sprite,
+    mapcache::TileAttributes,
+    consts::SCREEN_WIDTH,
+    super::types::{
+        Colour,
+        Pixel,
+        Layers
+    },
+    super::regs::VideoRegs,
+};
+
+const SPRITE_FETCH_DOTS: u32 = 6;
+const FETCH_TILE_STEP: u8 = 2;
+const FETCH_DATA_STEP: u8 = 6;
+const PUSH_STEP: u8 = 7;
+
+#[derive(Clone, Copy)]
+struct BGFifoPixel {
+    texel:      u8,
+    palette:    u8,
+    priority:   bool,   // CGB tile attribute priority
+    window:     bool,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ObjFifoPixel {
+    texel:          u8,
+    palette:        u8,     // 0 or 1 for GB, 0-7 for CGB
+    behind_bg:      bool,
+    order:          usize,  // Position in OAM scan, lower is higher priority when OPRI is set
+}
+
+#[derive(PartialEq)]
+enum Layer {
+    Background,
+    Window
+}
+
+pub struct PixelFifo {
+    bg_fifo:        VecDeque<BGFifoPixel>,
+    obj_fifo:       VecDeque<ObjFifoPixel>,
+
+    // Fetcher
+    fetcher_step:   u8,
+    fetcher_x:      u8,
+    layer:          Layer,
+    tile_num:       u8,
+    tile_attrs:     TileAttributes,
+    tile_y:         u8,
+    tile_texels:    [u8; 8],
+
+    // Sprites
+    sprites:        Vec<Sprite>,
+    sprite_fetched: Vec<bool>,
+    sprite_dots:    u32,
+    sprite_pending: usize,
+
+    // Output
+    x:              usize,
+    discard:        u8,
+    line:           Vec<Pixel>,
+    done:           bool,
+}
+
+impl PixelFifo {
+    pub fn new() -> Self {
+        PixelFifo {
+            bg_fifo:        VecDeque::with_capacity(16),
+            obj_fifo:       VecDeque::with_capacity(8),
+
+            fetcher_step:   0,
+            fetcher_x:      0,
+            layer:          Layer::Background,
+            tile_num:       0,
+            tile_attrs:     TileAttributes::default(),
+            tile_y:         0,
+            tile_texels:    [0; 8],
+
+            sprites:        Vec::new(),
+            sprite_fetched: Vec::new(),
+            sprite_dots:    0,
+            sprite_pending: 0,
+
+            x:              0,
+            discard:        0,
+            line:           vec![Pixel { colour: Colour::zero(), index: 0 }; SCREEN_WIDTH],
+            done:           false,
+        }
+    }
+
+    // Reset for a new line, and find the sprites on it. Call at the start of mode 3.
+    pub fn start_line(&mut self, vram: &VRAM, regs: &VideoRegs) {
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+
+        self.fetcher_step = 0;
+        self.fetcher_x = 0;
+        self.layer = Layer::Background;
+
+        self.sprites = vram.get_objects_for_line(regs.read_lcdc_y(), regs);
+        self.sprite_fetched = vec![false; self.sprites.len()];
+        self.sprite_dots = 0;
+
+        self.x = 0;
+        self.discard = regs.scroll_x % 8;
+        self.done = false;
+    }
+
+    // Run for a number of dots. Returns true when the line is finished.
+    pub fn step(&mut self, vram: &VRAM, regs: &VideoRegs, cgb_mode: bool, dots: u32) -> bool {
+        for _ in 0..dots {
+            if self.done {
+                break;
+            }
+            self.dot(vram, regs, cgb_mode);
+        }
+        self.done
+    }
+
+    pub fn get_line(&self) -> &[Pixel] {
+        &self.line
+    }
+
+    // True if the window was reached on this line.
+    pub fn drew_window(&self) -> bool {
+        self.layer == Layer::Window
+    }
+}
+
+// Internal
+impl PixelFifo {
+    fn dot(&mut self, vram: &VRAM, regs: &VideoRegs, cgb_mode: bool) {
+        // Fetching a sprite stalls everything else.
+        if self.sprite_dots > 0 {
+            self.sprite_dots -= 1;
+            if self.sprite_dots == 0 {
+                self.fetch_sprite(vram, regs, cgb_mode);
+            }
+            return;
+        }
+
+        if regs.display_sprites() {
+            // Sprites are fetched lowest X first, then in OAM order.
+            let x = self.x;
+            let next_sprite = self.sprites.iter().zip(self.sprite_fetched.iter()).enumerate()
+                .filter(|(_, (s, fetched))| !**fetched && ((s.x as usize) <= x + 8))
+                .min_by_key(|(i, (s, _))| (s.x, *i))
+                .map(|(i, _)| i);
+            if let Some(i) = next_sprite {
+                self.sprite_fetched[i] = true;
+                self.sprite_pending = i;
+                self.sprite_dots = SPRITE_FETCH_DOTS;
+                return;
+            }
+        }
+
+        // Switch to the window.
+        let window_x = regs.window_start_x();
+        if (self.layer == Layer::Background) && regs.is_window_on_line() && (self.x + 7 >= window_x as usize) {
+            self.layer = Layer::Window;
+            // If WX < 7, the left of the window is cut off instead of the fine scroll.
+            self.discard = 7_u8.saturating_sub(window_x);
+            self.fetcher_x = 0;
+            self.fetcher_step = 0;
+            self.bg_fifo.clear();
+        }
+
+        self.fetcher_step += 1;
+        match self.fetcher_step {
+            FETCH_TILE_STEP => self.fetch_tile_num(vram, regs, cgb_mode),
+            FETCH_DATA_STEP => self.fetch_tile_data(vram, regs),
+            PUSH_STEP => if self.bg_fifo.is_empty() {
+                let palette = (self.tile_attrs & TileAttributes::CGB_PAL).bits();
+                let priority = self.tile_attrs.contains(TileAttributes::PRIORITY);
+                let window = self.layer == Layer::Window;
+                for texel in self.tile_texels.iter() {
+                    self.bg_fifo.push_back(BGFifoPixel {
+                        texel: *texel,
+                        palette,
+                        priority,
+                        window
+                    });
+                }
+                self.fetcher_step = 0;
+                self.fetcher_x = self.fetcher_x.wrapping_add(1);
+            } else {
+                self.fetcher_step = PUSH_STEP - 1;
+            },
+            _ => {},
+        }
+
+        // Shift out a pixel.
+        if let Some(bg) = self.bg_fifo.pop_front() {
+            if self.discard > 0 {
+                self.discard -= 1;
+                return;
+            }
+
+            let obj = self.obj_fifo.pop_front();
+            self.line[self.x] = mix_pixel(self.x, bg, obj, vram, regs, cgb_mode);
+            self.x += 1;
+            self.done = self.x >= SCREEN_WIDTH;
+        }
+    }
+
+    fn fetch_tile_num(&mut self, vram: &VRAM, regs: &VideoRegs, cgb_mode: bool) {
+        let (map_x, map_y, map_select) = match self.layer {
+            Layer::Background => {
+                let y = regs.scroll_y.wrapping_add(regs.read_lcdc_y());
+                let x = (regs.scroll_x / 8).wrapping_add(self.fetcher_x) % 32;
+                self.tile_y = y % 8;
+                (x, y / 8, regs.bg_tile_map_select())
+            },
+            Layer::Window => {
+                let y = regs.window_line();
+                self.tile_y = y % 8;
+                (self.fetcher_x % 32, y / 8, regs.window_tile_map_select())
+            },
+        };
+
+        let index = ((map_y as usize) * 32) + (map_x as usize);
+        let (tile_map, tile_attrs) = if !map_select {
+            (&vram.tile_map_0, &vram.tile_attrs_0)
+        } else {
+            (&vram.tile_map_1, &vram.tile_attrs_1)
+        };
+
+        self.tile_num = tile_map[index];
+        self.tile_attrs = if cgb_mode {
+            TileAttributes::from_bits_truncate(tile_attrs[index])
+        } else {
+            TileAttributes::default()
+        };
+    }
+
+    fn fetch_tile_data(&mut self, vram: &VRAM, regs: &VideoRegs) {
+        let bank_offset = if self.tile_attrs.contains(TileAttributes::VRAM_BANK) {384} else {0};
+        let tile_index = if regs.lo_tile_data_select() {
+            self.tile_num as usize
+        } else {
+            let signed = self.tile_num as i8;
+            (256 + (signed as isize)) as usize
+        } + bank_offset;
+
+        let tile = vram.ref_tile(tile_index);
+        let y = if self.tile_attrs.contains(TileAttributes::Y_FLIP) {7 - self.tile_y} else {self.tile_y} as usize;
+        for (x, texel) in self.tile_texels.iter_mut().enumerate() {
+            let tex_x = if self.tile_attrs.contains(TileAttributes::X_FLIP) {7 - x} else {x};
+            *texel = tile.get_texel(tex_x, y);
+        }
+    }
+
+    // Mix the pending sprite into the sprite FIFO.
+    fn fetch_sprite(&mut self, vram: &VRAM, regs: &VideoRegs, cgb_mode: bool) {
+        let order = self.sprite_pending;
+        let sprite = self.sprites[order];
+        if !vram.is_object_visible(&sprite) {
+            return;
+        }
+        let (tile_num, tile_y) = sprite.tile_and_row(regs.read_lcdc_y(), regs.is_large_sprites());
+        let bank_offset = if cgb_mode {sprite.bank_offset()} else {0};
+        let tile = vram.ref_tile((tile_num as usize) + bank_offset);
+        let palette = if cgb_mode {sprite.cgb_palette()} else if sprite.palette_0() {0} else {1};
+
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjFifoPixel::default());
+        }
+
+        for i in 0..8 {
+            let slot = (sprite.x as isize) - 8 + i - (self.x as isize);
+            if slot < 0 {
+                continue;
+            }
+
+            let tile_x = if sprite.flip_x() {7 - i} else {i} as usize;
+            let new = ObjFifoPixel {
+                texel:      tile.get_texel(tile_x, tile_y as usize),
+                palette,
+                behind_bg:  !sprite.is_above_bg(),
+                order,
+            };
+
+            // Sprites are fetched in X order, so with X priority the sprite already in the FIFO wins.
+            // Otherwise, the sprite first in OAM has priority.
+            let old = &mut self.obj_fifo[slot as usize];
+            if (new.texel != 0) && ((old.texel == 0) || (!regs.is_x_priority() && (new.order < old.order))) {
+                *old = new;
+            }
+        }
+    }
+}
+
+// Resolve the final colour of a pixel.
+fn mix_pixel(x: usize, bg: BGFifoPixel, obj: Option<ObjFifoPixel>, vram: &VRAM, regs: &VideoRegs, cgb_mode: bool) -> Pixel {
+    let obj = obj.filter(|o| (o.texel != 0) && regs.display_sprites());
+    let layer = if bg.window {Layers::WINDOW} else {Layers::BACKGROUND};
+    let bg = if vram.is_layer_visible(layer) {bg} else {BGFifoPixel { texel: 0, ..bg }};
+
+    if cgb_mode {
+        // In CGB mode, LCDC bit 0 off means sprites are always on top.
+        match obj {
+            Some(o) if !regs.get_background_priority() || (bg.texel == 0) || (!bg.priority && !o.behind_bg) =>
+                vram.get_gbc_obj_pixel(o.palette, o.texel),
+            _ => vram.get_gbc_bg_pixel(bg.palette, bg.texel),
+        }
+    } else {
+        // In GB mode, LCDC bit 0 off means the background and window are blank.
+        let bg_texel = if regs.get_background_priority() {bg.texel} else {0};
+        let shade = match obj {
+            Some(o) if (bg_texel == 0) || !o.behind_bg => if o.palette == 0 {
+                vram.get_obj_0_shade(o.texel)
+            } else {
+                vram.get_obj_1_shade(o.texel)
+            },
+            _ => if regs.get_background_priority() {
+                vram.get_bg_shade(bg.texel)
+            } else {
+                Shade::default()
+            },
+        };
+        vram.get_gb_pixel(x, regs.read_lcdc_y(), shade)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::super::{
+        sgbpalettes::BW_PALETTE,
+        types::PixelFormat,
+        vram::read_pixel
+    };
+
+    // Two overlapping sprites on line 0, with the lower X sprite later in OAM.
+    // Sprite 0 is at X=8 with texel 1, sprite 1 is at X=2 with texel 3.
+    fn overlapping_sprites() -> (VRAM, VideoRegs) {
+        let mut vram = VRAM::new(BW_PALETTE, false, PixelFormat::RGBA8888);
+        let mut regs = VideoRegs::new(false);
+        regs.write_lcd_control(0x93);
+        vram.write(0xFF48, 0, 0xE4);
+        for row in 0..8 {
+            vram.write(0x8010 + (row * 2), 0, 0xFF);
+            vram.write(0x8020 + (row * 2), 0, 0xFF);
+            vram.write(0x8021 + (row * 2), 0, 0xFF);
+        }
+        for (loc, val) in [16, 8, 1, 0, 16, 2, 2, 0].iter().enumerate() {
+            vram.write(0xFE00 + (loc as u16), 0, *val);
+        }
+        (vram, regs)
+    }
+
+    #[test]
+    fn sprite_x_priority() {
+        let (mut vram, regs) = overlapping_sprites();
+        let line = draw_line(&mut vram, &regs);
+        assert_eq!(line[0..10], [3, 3, 1, 1, 1, 1, 1, 1, 0, 0]);
+    }
+
+    // Draw the current line with the pixel FIFO, and check the scanline renderer agrees.
+    fn draw_line(vram: &mut VRAM, regs: &VideoRegs) -> Vec<u8> {
+        let mut fifo = PixelFifo::new();
+        fifo.start_line(vram, regs);
+        assert!(fifo.step(vram, regs, false, 1000));
+
+        let mut target = vec![0; SCREEN_WIDTH * 144 * 4];
+        vram.draw_line_gb(&mut target, regs);
+        let start = (regs.read_lcdc_y() as usize) * SCREEN_WIDTH * 4;
+        for (x, (pixel, drawn)) in fifo.get_line().iter().zip(target[start..].chunks_exact(4)).enumerate() {
+            assert_eq!(pixel.colour, read_pixel(drawn, PixelFormat::RGBA8888).colour, "x = {}", x);
+        }
+        fifo.get_line().iter().map(|p| p.index).collect()
+    }
+
+    #[test]
+    fn window_wx_166() {
+        // The window is tile 1 (texel 3) everywhere, the background is blank.
+        let mut vram = VRAM::new(BW_PALETTE, false, PixelFormat::RGBA8888);
+        let mut regs = VideoRegs::new(false);
+        regs.write_lcd_control(0xF1);
+        vram.write(0xFF47, 0, 0xE4);
+        for row in 0..16 {
+            vram.write(0x8010 + row, 0, 0xFF);
+        }
+        for i in 0..0x400 {
+            vram.write(0x9C00 + i, 0, 1);
+        }
+        regs.window_y = 0;
+        regs.window_x = 166;
+        regs.check_window_y();
+
+        // Only the last pixel shows the window.
+        let line = draw_line(&mut vram, &regs);
+        assert_eq!(line[158], 0);
+        assert_eq!(line[159], 3);
+
+        // The window covers the whole of the next line.
+        regs.end_window_line(true);
+        regs.set_lcdc_y(1);
+        let line = draw_line(&mut vram, &regs);
+        assert!(line.iter().all(|i| *i == 3));
+
+        // Then it is back to the last pixel.
+        regs.window_x = 167;
+        regs.end_window_line(true);
+        regs.set_lcdc_y(2);
+        let line = draw_line(&mut vram, &regs);
+        assert!(line.iter().all(|i| *i == 0));
+    }
+}
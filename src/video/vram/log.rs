@@ -0,0 +1,151 @@
+// Memory access to VRAM, and the log of changes made to it.
+// The CPU thread and the renderer each have their own copy of VRAM.
+// The CPU thread writes to its copy directly, and records the changes and lines to draw over the frame.
+// The renderer replays the log on its copy once per frame.
+use super::{
+    VRAM,
+    Layers
+};
+use super::super::sgbpalettes::SGBPalette;
+use super::super::sgb::SuperGameBoy;
+use super::super::types::{
+    ColourCorrection,
+    Pixel
+};
+use super::super::regs::VideoRegs;
+use crate::mem::MemDevice;
+
+pub enum VRAMChange {
+    Write{loc: u16, bank: u8, val: u8}, // Write to tile data, maps, OAM or palettes.
+    InvalidateMaps,                     // Tile data addressing may have changed.
+    EnableSGB,
+    SGBCommand(Box<[u8]>),
+    Palette(SGBPalette),
+    ColourCorrection(ColourCorrection),
+    Layers(Layers),
+    ObjectVisible(usize, bool),
+}
+
+// An entry in the frame log, in the order it happened.
+pub enum LogEntry {
+    Change(VRAMChange),
+    DrawLineGB(VideoRegs),              // Registers at the start of mode 3.
+    DrawLineCGB(VideoRegs),
+    DrawLinePixels(u8, Box<[Pixel]>),   // A line finished by the pixel FIFO.
+}
+
+impl VRAM {
+    // Read from tile data, maps, OAM or palettes. Access should be checked first.
+    pub fn read(&self, loc: u16, bank: u8) -> u8 {
+        match loc {
+            // Raw tile data
+            0x8000..=0x97FF => {
+                let base = (loc - 0x8000) as usize + (bank as usize * 0x1800);
+                if (base & 1) == 0 {    // Lower bit
+                    self.tile_mem.get_pixel_lower_row(base)
+                } else {                // Upper bit
+                    self.tile_mem.get_pixel_upper_row(base)
+                }
+            },
+            // Background Map A
+            0x9800..=0x9BFF => {
+                let index = (loc - 0x9800) as usize;
+                if bank == 0 {self.tile_map_0[index]} else {self.tile_attrs_0[index]}
+            },
+            // Background Map B
+            0x9C00..=0x9FFF => {
+                let index = (loc - 0x9C00) as usize;
+                if bank == 0 {self.tile_map_1[index]} else {self.tile_attrs_1[index]}
+            },
+            // Sprite data
+            0xFE00..=0xFE9F => self.object_mem.read(loc - 0xFE00),
+            0xFF47 => self.palettes.read(0),
+            0xFF48 => self.palettes.read(1),
+            0xFF49 => self.palettes.read(2),
+            // Colour palettes
+            0xFF68 => self.colour_palettes.read_bg_index(),
+            0xFF69 => self.colour_palettes.read_bg(),
+            0xFF6A => self.colour_palettes.read_obj_index(),
+            0xFF6B => self.colour_palettes.read_obj(),
+            _ => 0xFF
+        }
+    }
+
+    // Write to tile data, maps, OAM or palettes. Access should be checked first.
+    pub fn write(&mut self, loc: u16, bank: u8, val: u8) {
+        match loc {
+            // Raw tile data
+            0x8000..=0x97FF => {
+                let base = (loc - 0x8000) as usize + (bank as usize * 0x1800);
+                if (base & 1) == 0 {    // Lower bit
+                    self.tile_mem.set_pixel_lower_row(base, val);
+                } else {                // Upper bit
+                    self.tile_mem.set_pixel_upper_row(base, val);
+                }
+                self.set_cache_0_dirty();
+                self.set_cache_1_dirty();
+            },
+            // Background Map A
+            0x9800..=0x9BFF => {
+                let index = (loc - 0x9800) as usize;
+                if bank == 0 {
+                    self.tile_map_0[index] = val;
+                } else {
+                    self.tile_attrs_0[index] = val;
+                }
+                self.set_cache_0_dirty();
+            },
+            // Background Map B
+            0x9C00..=0x9FFF => {
+                let index = (loc - 0x9C00) as usize;
+                if bank == 0 {
+                    self.tile_map_1[index] = val;
+                } else {
+                    self.tile_attrs_1[index] = val;
+                }
+                self.set_cache_1_dirty();
+            },
+            // Sprite data
+            0xFE00..=0xFE9F => self.object_mem.write(loc - 0xFE00, val),
+            0xFF47 => self.palettes.write(0, val),
+            0xFF48 => self.palettes.write(1, val),
+            0xFF49 => self.palettes.write(2, val),
+            // Colour palettes
+            0xFF68 => self.colour_palettes.write_bg_index(val),
+            0xFF69 => self.colour_palettes.write_bg(val),
+            0xFF6A => self.colour_palettes.write_obj_index(val),
+            0xFF6B => self.colour_palettes.write_obj(val),
+            _ => {}
+        }
+    }
+
+    // Replay a change made on the CPU thread.
+    pub fn apply(&mut self, change: VRAMChange) {
+        use VRAMChange::*;
+        match change {
+            Write{loc, bank, val} => self.write(loc, bank, val),
+            InvalidateMaps => {
+                self.set_cache_0_dirty();
+                self.set_cache_1_dirty();
+            },
+            EnableSGB => self.sgb = Some(SuperGameBoy::new()),
+            SGBCommand(data) => if let Some(sgb) = &mut self.sgb {
+                sgb.command(&data);
+            },
+            Palette(palette) => self.palettes.set_colours(palette),
+            ColourCorrection(correction) => self.colour_palettes.set_colour_correction(correction),
+            Layers(layers) => self.set_layers(layers),
+            ObjectVisible(index, visible) => self.set_object_visible(index, visible),
+        }
+    }
+
+    // Replay an entry from the frame log.
+    pub fn replay(&mut self, entry: LogEntry, target: &mut [u8]) {
+        match entry {
+            LogEntry::Change(change) => self.apply(change),
+            LogEntry::DrawLineGB(regs) => self.draw_line_gb(target, &regs),
+            LogEntry::DrawLineCGB(regs) => self.draw_line_cgb(target, &regs),
+            LogEntry::DrawLinePixels(y, mut pixels) => self.draw_line_pixels(target, y, &mut pixels),
+        }
+    }
+}
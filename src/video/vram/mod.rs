@@ -4,9 +4,15 @@ mod patternmem;
 mod sprite;
 mod palette;
 mod mapcache;
+mod fifo;
+mod log;
+#[cfg(feature = "debug")]
+mod debug;
 
 use super::types::{
-    Colour
+    Pixel,
+    PixelFormat,
+    Layers
 };
 use consts::*;
 use patternmem::*;
@@ -20,9 +26,21 @@ use sprite::{
 };
 use mapcache::*;
 use super::sgbpalettes::SGBPalette;
+use super::sgb::SuperGameBoy;
 
 use super::regs::VideoRegs;
 
+pub use drawing::{
+    write_pixel,
+    read_pixel,
+    Shade
+};
+pub use fifo::PixelFifo;
+pub use log::{
+    VRAMChange,
+    LogEntry
+};
+
 // VRAM is shared between threads and contains some cached data
 pub struct VRAM {
     // Raw tile mem and tile maps
@@ -40,10 +58,17 @@ pub struct VRAM {
     // Palettes
     pub palettes:           StaticPaletteMem,
     pub colour_palettes:    DynamicPaletteMem,
+    pub sgb:                Option<SuperGameBoy>,
+
+    pixel_format:           PixelFormat,
+
+    // Debug: layers and objects to draw
+    layers:                 Layers,
+    hidden_objects:         u64,
 }
 
 impl VRAM {
-    pub fn new(palette: SGBPalette, cgb_mode: bool) -> Self {
+    pub fn new(palette: SGBPalette, cgb_mode: bool, pixel_format: PixelFormat) -> Self {
         VRAM {
             tile_mem:           TileMem::new(if cgb_mode {TILE_DATA_HEIGHT_CGB} else {TILE_DATA_HEIGHT_GB} * TILE_DATA_WIDTH),
             tile_map_0:         vec![0; 32 * 32],
@@ -57,6 +82,12 @@ impl VRAM {
 
             palettes:           StaticPaletteMem::new(palette),
             colour_palettes:    DynamicPaletteMem::new(),
+            sgb:                None,
+
+            pixel_format,
+
+            layers:             Layers::all(),
+            hidden_objects:     0,
         }
     }
 }
@@ -94,29 +125,86 @@ impl VRAM {
         }
     }
 
+    // Get objects for the line, with the highest priority first.
+    // Objects with lower X have priority when the OPRI register (or DMG) requests it,
+    // otherwise objects earlier in OAM have priority.
+    pub fn get_objects_by_priority(&self, y: u8, regs: &VideoRegs) -> Vec<Sprite> {
+        let mut objects = self.get_objects_for_line(y, regs);
+        if regs.is_x_priority() {
+            // Stable sort keeps OAM order for objects with the same X.
+            objects.sort_by_key(|o| o.x);
+        }
+        objects.retain(|o| self.is_object_visible(o));
+        objects
+    }
+
+    pub fn set_layers(&mut self, layers: Layers) {
+        self.layers = layers;
+    }
+
+    pub fn set_object_visible(&mut self, index: usize, visible: bool) {
+        if index >= NUM_OBJECTS {
+            return;
+        }
+        if visible {
+            self.hidden_objects &= !(1 << index);
+        } else {
+            self.hidden_objects |= 1 << index;
+        }
+    }
+
+    #[inline]
+    pub fn is_layer_visible(&self, layer: Layers) -> bool {
+        self.layers.contains(layer)
+    }
+
+    // Hidden objects still take up their slot on the line, so timing doesn't change.
+    #[inline]
+    pub fn is_object_visible(&self, object: &Sprite) -> bool {
+        self.layers.contains(Layers::SPRITES) && ((self.hidden_objects & (1 << object.index)) == 0)
+    }
+
     #[inline]
-    pub fn get_bg_colour(&self, texel: u8) -> Colour {
-        self.palettes.get_colour(0, texel)
+    pub fn get_bg_shade(&self, texel: u8) -> Shade {
+        Shade { palette: 0, shade: self.palettes.get_shade(0, texel) }
     }
 
     #[inline]
-    pub fn get_obj_0_colour(&self, texel: u8) -> Colour {
-        self.palettes.get_colour(1, texel)
+    pub fn get_obj_0_shade(&self, texel: u8) -> Shade {
+        Shade { palette: 1, shade: self.palettes.get_shade(1, texel) }
     }
 
     #[inline]
-    pub fn get_obj_1_colour(&self, texel: u8) -> Colour {
-        self.palettes.get_colour(2, texel)
+    pub fn get_obj_1_shade(&self, texel: u8) -> Shade {
+        Shade { palette: 2, shade: self.palettes.get_shade(2, texel) }
     }
 
+    // Colour a GB pixel. In SGB mode, this depends on the position on screen.
     #[inline]
-    pub fn get_gbc_bg_colour(&self, which: u8, texel: u8) -> Colour {
-        self.colour_palettes.get_bg_colour(which as usize, texel)
+    pub fn get_gb_pixel(&self, x: usize, y: u8, shade: Shade) -> Pixel {
+        let colour = if let Some(sgb) = &self.sgb {
+            sgb.get_colour(x, y as usize, shade.shade)
+        } else {
+            self.palettes.get_shade_colour(shade.palette as usize, shade.shade)
+        };
+        Pixel { colour, index: shade.shade }
     }
 
+    // CGB pixels are indexed by their position in palette memory. Object palettes follow the background palettes.
     #[inline]
-    pub fn get_gbc_obj_colour(&self, which: u8, texel: u8) -> Colour {
-        self.colour_palettes.get_obj_colour(which as usize, texel)
+    pub fn get_gbc_bg_pixel(&self, which: u8, texel: u8) -> Pixel {
+        Pixel {
+            colour: self.colour_palettes.get_bg_colour(which as usize, texel),
+            index:  (which * 4) + texel
+        }
+    }
+
+    #[inline]
+    pub fn get_gbc_obj_pixel(&self, which: u8, texel: u8) -> Pixel {
+        Pixel {
+            colour: self.colour_palettes.get_obj_colour(which as usize, texel),
+            index:  32 + (which * 4) + texel
+        }
     }
 
     pub fn set_cache_0_dirty(&mut self) {
@@ -5,25 +5,11 @@ use crate::{
     mem::MemDevice,
     video::{
         PaletteColours,
-        Colour
+        Colour,
+        ColourCorrection
     }
 };
 
-const MAX_COLOUR: u16 = 0x1F;
-macro_rules! col15_to_col888 {
-    ($rgb:expr) => {
-        {
-            let r_i = ($rgb & MAX_COLOUR) << 3;
-            let g_i = (($rgb >> 5) & MAX_COLOUR) << 3;
-            let b_i = (($rgb >> 10) & MAX_COLOUR) << 3;
-            let r = r_i + (r_i >> 5);
-            let g = g_i + (g_i >> 5);
-            let b = b_i + (b_i >> 5);
-            Colour::new(r as u8, g as u8, b as u8)
-        }
-    };
-}
-
 bitflags! {
     #[derive(Default)]
     struct PaletteIndex: u8 {
@@ -35,6 +21,7 @@ bitflags! {
 struct DynamicPalette {
     colours:    PaletteColours,
     raw:        [u8; 8],
+    correction: ColourCorrection,
 }
 
 impl DynamicPalette {
@@ -42,8 +29,22 @@ impl DynamicPalette {
         DynamicPalette {
             colours:    [Colour::zero(); 4],
             raw:        [0; 8],
+            correction: ColourCorrection::None,
         }
     }
+
+    fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        self.correction = correction;
+        for colour in 0..4 {
+            self.decode(colour);
+        }
+    }
+
+    // Convert a colour from the raw 15-bit value.
+    fn decode(&mut self, colour: usize) {
+        let raw_idx = colour << 1;
+        self.colours[colour] = self.correction.convert(make_16!(self.raw[raw_idx + 1], self.raw[raw_idx]));
+    }
 }
 
 impl MemDevice for DynamicPalette {
@@ -54,9 +55,7 @@ impl MemDevice for DynamicPalette {
     fn write(&mut self, loc: u16, val: u8) {
         let colour = (loc >> 1) as usize;
         self.raw[(loc % 8) as usize] = val;
-
-        let raw_idx = colour << 1;
-        self.colours[colour] = col15_to_col888!(make_16!(self.raw[raw_idx + 1], self.raw[raw_idx]));
+        self.decode(colour);
     }
 }
 
@@ -84,6 +83,13 @@ impl DynamicPaletteMem {
         }
     }
 
+    // Change the colour correction, and convert existing colours.
+    pub fn set_colour_correction(&mut self, correction: ColourCorrection) {
+        for palette in self.bg_palettes.iter_mut().chain(self.obj_palettes.iter_mut()) {
+            palette.set_colour_correction(correction);
+        }
+    }
+
     pub fn get_bg_colour(&self, which: usize, texel: u8) -> Colour {
         self.bg_palettes[which].colours[texel as usize]
     }
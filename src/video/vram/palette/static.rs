@@ -6,9 +6,10 @@ use crate::video::{
 };
 
 // A palette with hard-coded colours.
+// Texels are first mapped to a shade by the palette register, then the shade is mapped to a colour.
 struct StaticPalette {
     colours: PaletteColours,
-    palette: PaletteColours,
+    shades: [u8; 4],
     raw: u8
 }
 
@@ -16,12 +17,7 @@ impl StaticPalette {
     pub fn new(colours: PaletteColours) -> Self {
         StaticPalette {
             colours: colours,
-            palette: [
-                colours[0],
-                colours[0],
-                colours[0],
-                colours[0]
-            ],
+            shades: [0; 4],
             raw: 0
         }
     }
@@ -33,15 +29,10 @@ impl StaticPalette {
     pub fn write(&mut self, val: u8) {
         self.raw = val;
 
-        let colour_0 = val & 0b00000011;
-        let colour_1 = (val & 0b00001100) >> 2;
-        let colour_2 = (val & 0b00110000) >> 4;
-        let colour_3 = (val & 0b11000000) >> 6;
-        
-        self.palette[0] = self.colours[colour_0 as usize];
-        self.palette[1] = self.colours[colour_1 as usize];
-        self.palette[2] = self.colours[colour_2 as usize];
-        self.palette[3] = self.colours[colour_3 as usize];
+        self.shades[0] = val & 0b00000011;
+        self.shades[1] = (val & 0b00001100) >> 2;
+        self.shades[2] = (val & 0b00110000) >> 4;
+        self.shades[3] = (val & 0b11000000) >> 6;
     }
 }
 
@@ -61,6 +52,12 @@ impl StaticPaletteMem {
         }
     }
 
+    pub fn set_colours(&mut self, colours: SGBPalette) {
+        self.palettes[0].colours = colours.bg;
+        self.palettes[1].colours = colours.obj0;
+        self.palettes[2].colours = colours.obj1;
+    }
+
     pub fn read(&self, which: usize) -> u8 {
         self.palettes[which].read()
     }
@@ -69,7 +66,13 @@ impl StaticPaletteMem {
         self.palettes[which].write(val);
     }
 
-    pub fn get_colour(&self, which: usize, texel: u8) -> Colour {
-        self.palettes[which].palette[texel as usize]
+    // Get the shade (0-3) for a texel.
+    pub fn get_shade(&self, which: usize, texel: u8) -> u8 {
+        self.palettes[which].shades[texel as usize]
     }
-}
\ No newline at end of file
+
+    // Get the colour for a shade.
+    pub fn get_shade_colour(&self, which: usize, shade: u8) -> Colour {
+        self.palettes[which].colours[shade as usize]
+    }
+}
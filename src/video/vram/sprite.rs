@@ -3,8 +3,11 @@ use bitflags::bitflags;
 
 use crate::mem::MemDevice;
 
+use super::consts::NUM_OBJECTS;
+
 const SPRITE_SMALL_HEIGHT: u8 = 8;
 const SPRITE_LARGE_HEIGHT: u8 = 16;
+const SPRITES_PER_LINE: usize = 10;
 
 bitflags! {
     #[derive(Default)]
@@ -20,6 +23,7 @@ bitflags! {
 
 #[derive(Clone, Copy)]
 pub struct Sprite {
+    pub index:      u8, // Position in OAM.
     pub y:          u8,
     pub x:          u8,
     pub tile_num:   u8,
@@ -27,8 +31,9 @@ pub struct Sprite {
 }
 
 impl Sprite {
-    pub fn new() -> Self {
+    pub fn new(index: u8) -> Self {
         Sprite {
+            index,
             y:          0,
             x:          0,
             tile_num:   0,
@@ -59,6 +64,21 @@ impl Sprite {
     pub fn cgb_palette(&self) -> u8 {
         (self.flags & SpriteFlags::CGB_PAL).bits()
     }
+
+    // Get the tile number and the row in the tile to draw for line y.
+    pub fn tile_and_row(&self, y: u8, large: bool) -> (u8, u8) {
+        let sprite_y = y + 16 - self.y;
+        let (tile_num_offset, tile_y) = match (large, sprite_y < 8, self.flip_y()) {
+            (false, true, false)    => (0_u8, sprite_y),
+            (false, true, true)     => (0_u8, 7 - sprite_y),
+            (true, true, false)     => (0_u8, sprite_y),
+            (true, false, false)    => (1_u8, sprite_y - 8),
+            (true, true, true)      => (1_u8, 7 - sprite_y),
+            (true, false, true)     => (0_u8, 15 - sprite_y),
+            _ => unreachable!("Cannot have small sprites with sprite_y >= 8")
+        };
+        (self.tile_num.wrapping_add(tile_num_offset), tile_y)
+    }
 }
 
 pub struct ObjectMem {
@@ -68,16 +88,22 @@ pub struct ObjectMem {
 impl ObjectMem {
     pub fn new() -> Self {
         ObjectMem {
-            objects: vec![Sprite::new(); 40],
+            objects: (0..NUM_OBJECTS as u8).map(Sprite::new).collect(),
         }
     }
 
+    #[cfg(feature = "debug")]
+    pub fn ref_objects(&self) -> &[Sprite] {
+        &self.objects
+    }
+
+    // OAM scan: find the first 10 objects in OAM that are on the line, regardless of X.
     pub fn get_objects_for_line(&self, y: u8, large: bool) -> Vec<Sprite> {
         let y_upper = y + 16;
         let y_lower = y_upper - if large {SPRITE_LARGE_HEIGHT} else {SPRITE_SMALL_HEIGHT};
         self.objects.iter().filter(|o| {
             (o.y > y_lower) && (o.y <= y_upper)
-        }).cloned().collect::<Vec<_>>()
+        }).take(SPRITES_PER_LINE).cloned().collect::<Vec<_>>()
     }
 }
 